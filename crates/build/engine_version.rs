@@ -0,0 +1,50 @@
+// Shared by the executors' build scripts through `include!`, records the
+// version of an engine package resolved in Cargo.lock as `ENGINE_VERSION`.
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+fn record_engine_version(package: &str) {
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-changed=../build/engine_version.rs");
+    let manifest_dir = PathBuf::from(env::var_os("CARGO_MANIFEST_DIR").unwrap());
+    let version = match find_lockfile(&manifest_dir) {
+        Some(lockfile) => {
+            println!("cargo:rerun-if-changed={}", lockfile.display());
+            fs::read_to_string(&lockfile)
+                .ok()
+                .and_then(|lock| locked_version(&lock, package))
+        }
+        None => None,
+    };
+    println!(
+        "cargo:rustc-env=ENGINE_VERSION={}",
+        version.as_deref().unwrap_or("unknown")
+    );
+}
+
+/// The Cargo.lock of the workspace the crate is built in, the closest one
+/// up from the manifest.
+fn find_lockfile(dir: &Path) -> Option<PathBuf> {
+    dir.ancestors()
+        .map(|d| d.join("Cargo.lock"))
+        .find(|lockfile| lockfile.is_file())
+}
+
+/// The version of the first `[[package]]` called `name`.
+fn locked_version(lock: &str, name: &str) -> Option<String> {
+    let mut in_package = false;
+    for line in lock.lines().map(str::trim) {
+        if line.starts_with('[') {
+            in_package = false;
+        } else if let Some(value) = line.strip_prefix("name = ") {
+            in_package = value.trim_matches('"') == name;
+        } else if let Some(value) = line.strip_prefix("version = ") {
+            if in_package {
+                return Some(value.trim_matches('"').to_string());
+            }
+        }
+    }
+    None
+}
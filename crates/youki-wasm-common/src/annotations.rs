@@ -0,0 +1,113 @@
+//! Typed access to the `youki.wasm.*` annotations.
+
+use crate::OptLevel;
+use anyhow::{bail, Context, Result};
+use oci_spec::runtime::Spec;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+/// Annotation naming the engine that should run the workload.
+pub const HANDLER_ANNOTATION: &str = "youki.wasm.handler";
+
+/// Annotation naming the module to run instead of `args[0]`, for images
+/// that ship several modules.
+pub const ENTRYPOINT_ANNOTATION: &str = "youki.wasm.entrypoint";
+
+/// Annotation pointing at a file of `KEY=VALUE` lines to add to the guest's
+/// environment, so secrets can stay out of the spec.
+pub const ENV_FILE_ANNOTATION: &str = "youki.wasm.env-file";
+
+/// Annotation telling where the module comes from. `stdin` reads it from
+/// the container's stdin.
+pub const MODULE_ANNOTATION: &str = "youki.wasm.module";
+
+/// Annotation capping the size of a module read from stdin, in bytes.
+pub const STDIN_MAX_BYTES_ANNOTATION: &str = "youki.wasm.stdin-max-bytes";
+
+/// Annotation that, set to `true`, preopens the container's root as the
+/// guest's `/`.
+pub const PREOPEN_ROOT_ANNOTATION: &str = "youki.wasm.preopen-root";
+
+/// Annotation holding the nice value of the container's init process.
+pub const NICE_ANNOTATION: &str = "youki.wasm.nice";
+
+/// Annotation picking how much the engine optimizes the compiled module,
+/// see `OptLevel`.
+pub const OPT_LEVEL_ANNOTATION: &str = "youki.wasm.opt_level";
+
+/// The `youki.wasm.*` annotations of a spec. Values with a type are parsed,
+/// an invalid one is an error naming the annotation. Other annotations are
+/// ignored.
+#[derive(Clone, Copy)]
+pub struct WasmAnnotations<'a> {
+    annotations: Option<&'a HashMap<String, String>>,
+}
+
+impl<'a> WasmAnnotations<'a> {
+    pub fn new(spec: &'a Spec) -> Self {
+        WasmAnnotations {
+            annotations: spec.annotations().as_ref(),
+        }
+    }
+
+    fn get(&self, key: &str) -> Option<&'a str> {
+        self.annotations?.get(key).map(String::as_str)
+    }
+
+    pub fn handler(&self) -> Option<&'a str> {
+        self.get(HANDLER_ANNOTATION)
+    }
+
+    pub fn entrypoint(&self) -> Option<&'a str> {
+        self.get(ENTRYPOINT_ANNOTATION)
+    }
+
+    pub fn env_file(&self) -> Option<PathBuf> {
+        self.get(ENV_FILE_ANNOTATION).map(PathBuf::from)
+    }
+
+    pub fn module(&self) -> Option<&'a str> {
+        self.get(MODULE_ANNOTATION)
+    }
+
+    pub fn stdin_max_bytes(&self) -> Result<Option<u64>> {
+        self.parse(STDIN_MAX_BYTES_ANNOTATION)
+    }
+
+    /// `false` unless annotated, the value is compared case-insensitively.
+    pub fn preopen_root(&self) -> Result<bool> {
+        match self.get(PREOPEN_ROOT_ANNOTATION) {
+            Some(v) if v.eq_ignore_ascii_case("true") => Ok(true),
+            Some(v) if v.eq_ignore_ascii_case("false") => Ok(false),
+            Some(v) => {
+                bail!("invalid {PREOPEN_ROOT_ANNOTATION} annotation {v:?}, expected true or false")
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// The requested nice value as given, possibly out of the kernel's range.
+    pub fn nice(&self) -> Result<Option<i32>> {
+        self.parse(NICE_ANNOTATION)
+    }
+
+    pub fn opt_level(&self) -> Result<Option<OptLevel>> {
+        self.parse(OPT_LEVEL_ANNOTATION)
+    }
+
+    fn parse<T>(&self, key: &str) -> Result<Option<T>>
+    where
+        T: FromStr,
+        T::Err: Into<anyhow::Error>,
+    {
+        self.get(key)
+            .map(|v| {
+                v.trim()
+                    .parse::<T>()
+                    .map_err(Into::<anyhow::Error>::into)
+                    .with_context(|| format!("invalid {key} annotation {v:?}"))
+            })
+            .transpose()
+    }
+}
@@ -0,0 +1,649 @@
+//! Spec helpers shared by the wasm executors.
+
+use anyhow::{anyhow, bail, Context, Result};
+use oci_spec::runtime::{Mount, Spec};
+use std::collections::BTreeMap;
+use std::ffi::CString;
+use std::fs;
+use std::io::{self, Read};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+
+mod annotations;
+
+pub use annotations::*;
+
+/// `args[0]` that has the module read from stdin.
+pub const STDIN_MODULE_ARG: &str = "-";
+const DEFAULT_STDIN_MAX_BYTES: u64 = 64 * 1024 * 1024;
+/// Name of a container's init process once its module runs, see
+/// `mark_running`.
+pub const RUNNING_PROCESS_NAME: &str = "youki-wasm";
+/// Shells whose `-c` wrapper is taken off the args, see `get_args`.
+const SHELLS: &[&str] = &["sh", "bash", "ash", "dash"];
+
+/// Magic number opening every wasm binary, core module or component.
+const WASM_MAGIC: &[u8] = b"\0asm";
+/// Layer field of the binary header, after the 2-byte version: 0 for core
+/// modules, 1 for components.
+const COMPONENT_LAYER: &[u8] = &[0x01, 0x00];
+
+/// Whether the executor called `name` should run the workload. An explicit
+/// `youki.wasm.handler` annotation decides, compared case-insensitively.
+/// Without it, any wasm executor claims a workload whose module, the
+/// entrypoint or `args[0]`, is a `.wasm` file.
+pub fn can_handle(spec: &Spec, name: &str) -> bool {
+    let annotations = WasmAnnotations::new(spec);
+    if let Some(handler) = annotations.handler() {
+        return handler.eq_ignore_ascii_case(name);
+    }
+    module_from_stdin(spec)
+        || annotations
+            .entrypoint()
+            .or_else(|| get_args(spec).first().copied())
+            .map(|cmd| cmd.ends_with(".wasm"))
+            .unwrap_or(false)
+}
+
+/// Whether the module is piped in on stdin rather than read from a file,
+/// requested with `youki.wasm.module=stdin` or `-` as `args[0]`. Meant for
+/// quick experiments, the guest then sees its stdin already consumed.
+pub fn module_from_stdin(spec: &Spec) -> bool {
+    let annotations = WasmAnnotations::new(spec);
+    if let Some(module) = annotations.module() {
+        return module == "stdin";
+    }
+    annotations.entrypoint().is_none() && get_args(spec).first().copied() == Some(STDIN_MODULE_ARG)
+}
+
+/// Whether the guest gets the container's root as `/`, requested with
+/// `youki.wasm.preopen-root=true`. The executors run after the pivot into the
+/// bundle's rootfs, so absolute guest paths resolve inside the rootfs and
+/// never reach the host. The `preopen_dirs` are still preopened on their own
+/// and win for paths below them.
+pub fn preopen_root(spec: &Spec) -> Result<bool> {
+    WasmAnnotations::new(spec).preopen_root()
+}
+
+/// Directories the guest gets access to: the destinations of the spec's bind
+/// mounts, each once. The executor runs inside the container, so the mounts
+/// are already in place and the destination is both the host-side and the
+/// guest path. Read-only mounts are preopened too, the kernel keeps them
+/// read-only.
+pub fn preopen_dirs(spec: &Spec) -> Vec<PathBuf> {
+    let mut dirs: Vec<PathBuf> = Vec::new();
+    let destinations = spec
+        .mounts()
+        .as_ref()
+        .into_iter()
+        .flatten()
+        .filter(|m| is_bind_mount(m))
+        .map(|m| m.destination());
+    for dir in destinations {
+        // bind mounted files such as /etc/hosts can't be preopened
+        if dir.is_dir() && !dirs.contains(dir) {
+            dirs.push(dir.clone());
+        }
+    }
+    dirs
+}
+
+fn is_bind_mount(mount: &Mount) -> bool {
+    mount.typ().as_deref() == Some("bind")
+        || mount
+            .options()
+            .as_ref()
+            .map(|o| o.iter().any(|o| o == "bind" || o == "rbind"))
+            .unwrap_or(false)
+}
+
+/// How much the engine optimizes a module while compiling it. Short-lived
+/// jobs start faster with `None`, long-running services run faster with the
+/// higher levels.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OptLevel {
+    None,
+    #[default]
+    Speed,
+    SpeedAndSize,
+}
+
+impl FromStr for OptLevel {
+    type Err = anyhow::Error;
+
+    /// Parses the names used in options.json and the annotation.
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "none" => Ok(OptLevel::None),
+            "speed" => Ok(OptLevel::Speed),
+            "speed_and_size" => Ok(OptLevel::SpeedAndSize),
+            _ => bail!("invalid opt level {s:?}, expected none, speed or speed_and_size"),
+        }
+    }
+}
+
+/// The optimization level of the workload: the `youki.wasm.opt_level`
+/// annotation, else `default` as configured for the shim.
+pub fn opt_level(spec: &Spec, default: OptLevel) -> Result<OptLevel> {
+    Ok(WasmAnnotations::new(spec).opt_level()?.unwrap_or(default))
+}
+
+/// Reads the module from stdin. Inputs larger than the
+/// `youki.wasm.stdin-max-bytes` annotation, 64 MiB by default, are rejected.
+pub fn read_stdin_module(spec: &Spec) -> Result<Vec<u8>> {
+    let max = WasmAnnotations::new(spec)
+        .stdin_max_bytes()?
+        .unwrap_or(DEFAULT_STDIN_MAX_BYTES);
+    let mut bytes = Vec::new();
+    io::stdin()
+        .lock()
+        .take(max + 1)
+        .read_to_end(&mut bytes)
+        .context("could not read module from stdin")?;
+    if bytes.len() as u64 > max {
+        bail!("module on stdin exceeds {max} bytes");
+    }
+    Ok(bytes)
+}
+
+/// The workload's args. Tooling sometimes wraps the command in a shell, as in
+/// `/bin/sh -c "app.wasm arg"`; such a wrapper is taken off when the command
+/// runs a `.wasm` module and is made of plain words only, anything needing a
+/// real shell is left alone.
+pub fn get_args(spec: &Spec) -> Vec<&str> {
+    let args: Vec<&str> = spec
+        .process()
+        .as_ref()
+        .and_then(|p| p.args().as_deref())
+        .unwrap_or_default()
+        .iter()
+        .map(String::as_str)
+        .collect();
+    match shell_command(&args) {
+        Some(command) if is_plain_command(command) => {
+            let words: Vec<&str> = command.split_whitespace().collect();
+            if words.first().is_some_and(|w| w.ends_with(".wasm")) {
+                return words;
+            }
+            args
+        }
+        _ => args,
+    }
+}
+
+/// The command of a `sh -c <command>` wrapper.
+fn shell_command<'a>(args: &[&'a str]) -> Option<&'a str> {
+    match args {
+        [shell, "-c", command] if is_shell(shell) => Some(command),
+        _ => None,
+    }
+}
+
+fn is_shell(path: &str) -> bool {
+    Path::new(path)
+        .file_name()
+        .and_then(|name| name.to_str())
+        .is_some_and(|name| SHELLS.contains(&name))
+}
+
+/// Whether the shell would just split `command` on whitespace, without
+/// quoting, expansion, redirection or chaining.
+fn is_plain_command(command: &str) -> bool {
+    !command.contains(|c: char| "\\'\"`$;&|<>(){}*?~#!".contains(c))
+}
+
+/// The guest's argv: the module to run followed by the remaining args.
+pub fn guest_args(spec: &Spec) -> Vec<String> {
+    let args = get_args(spec);
+    match WasmAnnotations::new(spec).entrypoint() {
+        Some(entrypoint) => std::iter::once(entrypoint)
+            .chain(args.into_iter().skip(1))
+            .map(str::to_string)
+            .collect(),
+        None => args.into_iter().map(str::to_string).collect(),
+    }
+}
+
+/// The module to run, taken from the `youki.wasm.entrypoint` annotation or
+/// else from `args[0]`. A relative path resolves against `process.cwd`, which
+/// libcontainer enters before handing over to the executor. The entrypoint
+/// comes from the image, so it may not climb out of the rootfs with `..`.
+pub fn module_path(spec: &Spec) -> Result<&Path> {
+    let cmd = match WasmAnnotations::new(spec).entrypoint() {
+        Some(entrypoint) => {
+            if Path::new(entrypoint)
+                .components()
+                .any(|c| c == Component::ParentDir)
+            {
+                bail!("entrypoint {entrypoint:?} must stay within the rootfs");
+            }
+            entrypoint
+        }
+        None => {
+            let args = get_args(spec);
+            let cmd = *args
+                .first()
+                .ok_or_else(|| anyhow!("no module specified in process args"))?;
+            if is_shell(cmd) {
+                bail!(
+                    "can't run {cmd:?} as a wasm module: only shell wrappers of the form \
+                     `sh -c \"module.wasm args\"` without quoting or expansion are supported"
+                );
+            }
+            cmd
+        }
+    };
+    let path = Path::new(cmd);
+    if path.file_name().is_none() {
+        bail!("{cmd:?} does not name a module file");
+    }
+    Ok(path)
+}
+
+/// Where the `module_path` of the spec is found in `rootfs` when looked at
+/// from outside the container, e.g. to validate a bundle before running it.
+pub fn module_in_rootfs(spec: &Spec, rootfs: &Path) -> Result<PathBuf> {
+    let cmd = module_path(spec)?;
+    let cwd = spec
+        .process()
+        .as_ref()
+        .map(|p| p.cwd().as_path())
+        .unwrap_or(Path::new("/"));
+    // absolute paths replace the cwd when joined
+    Ok(rootfs.join(cwd.join(cmd).strip_prefix("/").unwrap_or(cmd)))
+}
+
+/// Whether `bytes` is a component-model binary rather than a core module.
+pub fn is_component(bytes: &[u8]) -> bool {
+    bytes.starts_with(WASM_MAGIC) && bytes.get(6..8) == Some(COMPONENT_LAYER)
+}
+
+/// Fails with an actionable error for components, which the executors can't
+/// run, instead of leaving them to a generic compile failure.
+pub fn ensure_core_module(bytes: &[u8], path: &Path) -> Result<()> {
+    if is_component(bytes) {
+        bail!(
+            "{} is a wasm component, but only core WASI modules are supported; \
+             build it for wasm32-wasi without componentizing it",
+            path.display()
+        );
+    }
+    Ok(())
+}
+
+/// Splits the spec's `KEY=VALUE` env entries, skipping malformed entries and
+/// entries that contain NUL bytes, which WASI can't represent.
+pub fn env_iter(spec: &Spec) -> impl Iterator<Item = (String, String)> + '_ {
+    spec.process()
+        .as_ref()
+        .and_then(|p| p.env().as_ref())
+        .into_iter()
+        .flatten()
+        .filter(|e| !e.contains('\0'))
+        .filter_map(|e| e.split_once('='))
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+}
+
+/// The guest's environment: the shim's `defaults`, the spec's hostname, the
+/// entries of the `youki.wasm.env-file` file, if annotated, and the spec's
+/// env, each winning over the ones before on conflicting keys. Keys are
+/// unique with the last entry winning, and sorted so that every run sees the
+/// same environment.
+///
+/// WASI has no way to read the hostname libcontainer sets in the UTS
+/// namespace, so the spec's hostname is passed as `HOSTNAME` unless the env
+/// sets one itself.
+pub fn env_vars(spec: &Spec, defaults: &[(String, String)]) -> Result<Vec<(String, String)>> {
+    let mut envs: BTreeMap<String, String> = defaults.iter().cloned().collect();
+    if let Some(hostname) = spec.hostname().as_deref().filter(|h| !h.is_empty()) {
+        envs.insert("HOSTNAME".to_string(), hostname.to_string());
+    }
+    if let Some(path) = WasmAnnotations::new(spec).env_file() {
+        envs.extend(read_env_file(&path)?);
+    }
+    envs.extend(env_iter(spec));
+    Ok(envs.into_iter().collect())
+}
+
+/// Parses an env file, skipping blank lines, `#` comments and entries that
+/// `env_iter` would skip.
+fn read_env_file(path: &Path) -> Result<Vec<(String, String)>> {
+    let data = fs::read_to_string(path)
+        .with_context(|| format!("could not read env file {}", path.display()))?;
+    Ok(data
+        .lines()
+        .filter(|l| !l.trim().is_empty() && !l.trim_start().starts_with('#'))
+        .filter(|l| !l.contains('\0'))
+        .filter_map(|l| l.split_once('='))
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect())
+}
+
+/// Renames the calling process to `RUNNING_PROCESS_NAME`. Executors call it
+/// once the module is compiled and about to run, which is how the shim tells
+/// that a start finished, see its `start_timeout_secs` option.
+pub fn mark_running() {
+    let name = CString::new(RUNNING_PROCESS_NAME).expect("process name has no NUL");
+    // Safety: PR_SET_NAME reads a NUL-terminated string of at most 16 bytes.
+    if unsafe { libc::prctl(libc::PR_SET_NAME, name.as_ptr()) } != 0 {
+        log::debug!("could not rename process: {}", io::Error::last_os_error());
+    }
+}
+
+/// Runs `f`, turning a panic into an error with the panic message, so that
+/// it is reported like any other executor failure. The panic hook still
+/// prints the location, and the backtrace with `RUST_BACKTRACE=1`.
+pub fn catch_panic<T>(f: impl FnOnce() -> Result<T>) -> Result<T> {
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(res) => res,
+        Err(payload) => {
+            let msg = payload
+                .downcast_ref::<&str>()
+                .map(|s| s.to_string())
+                .or_else(|| payload.downcast_ref::<String>().cloned())
+                .unwrap_or_else(|| "unknown cause".to_string());
+            Err(anyhow!("executor panicked: {}", msg))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spec(args: &[&str], annotations: &[(&str, &str)]) -> Spec {
+        let mut spec = Spec::default();
+        let mut process = spec.process().clone().unwrap_or_default();
+        process
+            .set_args(Some(args.iter().map(|a| a.to_string()).collect()))
+            .set_env(None);
+        spec.set_process(Some(process));
+        spec.set_hostname(None);
+        spec.set_annotations(Some(
+            annotations
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+        ));
+        spec
+    }
+
+    fn with_env(mut spec: Spec, env: &[&str]) -> Spec {
+        let mut process = spec.process().clone().unwrap();
+        process.set_env(Some(env.iter().map(|e| e.to_string()).collect()));
+        spec.set_process(Some(process));
+        spec
+    }
+
+    fn pairs(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    fn mount(destination: &Path, typ: &str, options: &[&str]) -> Mount {
+        let mut mount = Mount::default();
+        mount
+            .set_destination(destination.to_path_buf())
+            .set_typ(Some(typ.to_string()))
+            .set_options(Some(options.iter().map(|o| o.to_string()).collect()));
+        mount
+    }
+
+    #[test]
+    fn preopen_dirs_are_bind_mounted_dirs() {
+        let dir = tempfile::tempdir().unwrap();
+        let data = dir.path().join("data");
+        let ro = dir.path().join("ro");
+        let hosts = dir.path().join("hosts");
+        fs::create_dir(&data).unwrap();
+        fs::create_dir(&ro).unwrap();
+        fs::write(&hosts, "").unwrap();
+        let mut s = spec(&["/app.wasm"], &[]);
+        s.set_mounts(Some(vec![
+            mount(&data, "bind", &["rbind"]),
+            mount(&ro, "none", &["bind", "ro"]),
+            // the same destination mounted again is preopened once
+            mount(&data, "bind", &["rbind", "ro"]),
+            mount(&hosts, "bind", &["bind"]),
+            mount(&dir.path().join("missing"), "bind", &["bind"]),
+            mount(Path::new("/proc"), "proc", &[]),
+        ]));
+        assert_eq!(preopen_dirs(&s), [data, ro]);
+
+        s.set_mounts(None);
+        assert!(preopen_dirs(&s).is_empty());
+        s.set_mounts(Some(Vec::new()));
+        assert!(preopen_dirs(&s).is_empty());
+    }
+
+    #[test]
+    fn opt_level_annotation_wins_over_default() {
+        let s = spec(&["/app.wasm"], &[]);
+        assert_eq!(opt_level(&s, OptLevel::None).unwrap(), OptLevel::None);
+        assert_eq!(opt_level(&s, OptLevel::default()).unwrap(), OptLevel::Speed);
+        let s = spec(&["/app.wasm"], &[(OPT_LEVEL_ANNOTATION, "speed_and_size")]);
+        assert_eq!(
+            opt_level(&s, OptLevel::None).unwrap(),
+            OptLevel::SpeedAndSize
+        );
+        let s = spec(&["/app.wasm"], &[(OPT_LEVEL_ANNOTATION, "fast")]);
+        assert!(opt_level(&s, OptLevel::None).is_err());
+    }
+
+    #[test]
+    fn env_vars_include_defaults_unless_overridden() {
+        let defaults = pairs(&[("REGION", "eu"), ("NODE", "n1"), ("HOSTNAME", "node")]);
+        let s = with_env(spec(&["/app.wasm"], &[]), &["NODE=spec"]);
+        assert_eq!(
+            env_vars(&s, &defaults).unwrap(),
+            pairs(&[("HOSTNAME", "node"), ("NODE", "spec"), ("REGION", "eu")])
+        );
+
+        let mut s = s;
+        s.set_hostname(Some("box".to_string()));
+        let envs = env_vars(&s, &defaults).unwrap();
+        assert!(envs.contains(&("HOSTNAME".to_string(), "box".to_string())));
+    }
+
+    #[test]
+    fn can_handle_follows_handler_annotation() {
+        let s = spec(&["/app.wasm"], &[(HANDLER_ANNOTATION, "WasmTime")]);
+        assert!(can_handle(&s, "wasmtime"));
+        assert!(!can_handle(&s, "wasmedge"));
+        // the annotation wins over the module's extension
+        let s = spec(&["/app.wasm"], &[(HANDLER_ANNOTATION, "default")]);
+        assert!(!can_handle(&s, "wasmtime"));
+        let s = spec(&["/bin/app"], &[(HANDLER_ANNOTATION, "wasmtime")]);
+        assert!(can_handle(&s, "wasmtime"));
+    }
+
+    #[test]
+    fn can_handle_without_annotation() {
+        assert!(can_handle(&spec(&["/app.wasm", "x"], &[]), "wasmtime"));
+        assert!(can_handle(&spec(&["/app.wasm"], &[]), "wasmedge"));
+        assert!(!can_handle(&spec(&["/bin/app"], &[]), "wasmtime"));
+        assert!(!can_handle(&spec(&[], &[]), "wasmtime"));
+        let s = spec(&["/bin/app"], &[(ENTRYPOINT_ANNOTATION, "/app.wasm")]);
+        assert!(can_handle(&s, "wasmtime"));
+    }
+
+    #[test]
+    fn module_path_takes_args0_with_or_without_separator() {
+        let s = spec(&["app.wasm", "a", "b"], &[]);
+        assert_eq!(module_path(&s).unwrap(), Path::new("app.wasm"));
+        assert_eq!(guest_args(&s), ["app.wasm", "a", "b"]);
+        let s = spec(&["/srv/app.wasm", "a"], &[]);
+        assert_eq!(module_path(&s).unwrap(), Path::new("/srv/app.wasm"));
+        assert!(module_path(&spec(&[], &[])).is_err());
+        assert!(module_path(&spec(&["/"], &[])).is_err());
+    }
+
+    #[test]
+    fn env_vars_spec_wins_over_env_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let env_file = dir.path().join("env");
+        fs::write(&env_file, "# comment\n\nA=file\nB=file\nNOVALUE\n").unwrap();
+        let s = with_env(
+            spec(
+                &["/app.wasm"],
+                &[(ENV_FILE_ANNOTATION, env_file.to_str().unwrap())],
+            ),
+            &["B=spec"],
+        );
+        assert_eq!(
+            env_vars(&s, &[]).unwrap(),
+            pairs(&[("A", "file"), ("B", "spec")])
+        );
+
+        let s = spec(&["/app.wasm"], &[(ENV_FILE_ANNOTATION, "/nonexistent/env")]);
+        assert!(env_vars(&s, &[]).is_err());
+    }
+
+    #[test]
+    fn is_component_checks_layer() {
+        assert!(!is_component(b"\0asm\x01\0\0\0"));
+        assert!(is_component(b"\0asm\x0d\0\x01\0"));
+        assert!(!is_component(b"\0asm"));
+        assert!(!is_component(b"\x7fELF\x02\x01\x01\0"));
+        assert!(ensure_core_module(b"\0asm\x0d\0\x01\0", Path::new("c.wasm")).is_err());
+        assert!(ensure_core_module(b"\0asm\x01\0\0\0", Path::new("m.wasm")).is_ok());
+    }
+
+    #[test]
+    fn module_path_rejects_parent_entrypoints() {
+        for entrypoint in ["../host.wasm", "/app/../../host.wasm"] {
+            let s = spec(&["x"], &[(ENTRYPOINT_ANNOTATION, entrypoint)]);
+            assert!(module_path(&s).is_err(), "{:?}", entrypoint);
+        }
+        let s = spec(&["x", "arg"], &[(ENTRYPOINT_ANNOTATION, "app/main.wasm")]);
+        assert_eq!(module_path(&s).unwrap(), Path::new("app/main.wasm"));
+        // the entrypoint replaces args[0], the other args are kept
+        assert_eq!(guest_args(&s), ["app/main.wasm", "arg"]);
+    }
+
+    #[test]
+    fn env_vars_are_deduplicated_last_wins() {
+        let s = with_env(
+            spec(&["/app.wasm"], &[]),
+            &["A=1", "B=2", "A=3", "C=x=y", "BAD", "D=\0"],
+        );
+        assert_eq!(
+            env_vars(&s, &[]).unwrap(),
+            pairs(&[("A", "3"), ("B", "2"), ("C", "x=y")])
+        );
+    }
+
+    #[test]
+    fn env_vars_pass_hostname_unless_set() {
+        let mut s = spec(&["/app.wasm"], &[]);
+        s.set_hostname(Some("box".to_string()));
+        assert_eq!(env_vars(&s, &[]).unwrap(), pairs(&[("HOSTNAME", "box")]));
+
+        let s = with_env(s, &["HOSTNAME=env"]);
+        assert_eq!(env_vars(&s, &[]).unwrap(), pairs(&[("HOSTNAME", "env")]));
+
+        let mut s = spec(&["/app.wasm"], &[]);
+        s.set_hostname(Some(String::new()));
+        assert!(env_vars(&s, &[]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn get_args_unwraps_plain_shell_wrappers() {
+        let s = spec(&["/bin/sh", "-c", "app.wasm --flag  x"], &[]);
+        assert_eq!(get_args(&s), ["app.wasm", "--flag", "x"]);
+        let s = spec(&["bash", "-c", "/app.wasm"], &[]);
+        assert_eq!(get_args(&s), ["/app.wasm"]);
+        assert!(can_handle(&s, "wasmtime"));
+    }
+
+    #[test]
+    fn get_args_keeps_real_shell_commands() {
+        for command in [
+            "app.wasm $HOME",
+            "app.wasm 'a b'",
+            "app.wasm | cat",
+            "echo hi",
+        ] {
+            let s = spec(&["/bin/sh", "-c", command], &[]);
+            assert_eq!(get_args(&s), ["/bin/sh", "-c", command]);
+            assert!(module_path(&s).is_err(), "{:?}", command);
+        }
+        let s = spec(&["/bin/zsh", "-c", "app.wasm"], &[]);
+        assert_eq!(get_args(&s), ["/bin/zsh", "-c", "app.wasm"]);
+    }
+
+    #[test]
+    fn parses_known_annotations() {
+        let s = spec(
+            &["/app.wasm"],
+            &[
+                (HANDLER_ANNOTATION, "wasmtime"),
+                (ENTRYPOINT_ANNOTATION, "/main.wasm"),
+                (ENV_FILE_ANNOTATION, "/etc/app.env"),
+                (MODULE_ANNOTATION, "stdin"),
+                (STDIN_MAX_BYTES_ANNOTATION, "1024"),
+                (PREOPEN_ROOT_ANNOTATION, "TRUE"),
+                (NICE_ANNOTATION, " -5 "),
+                (OPT_LEVEL_ANNOTATION, "none"),
+                ("youki.wasm.unknown", "x"),
+                ("io.kubernetes.cri.container-type", "container"),
+            ],
+        );
+        let a = WasmAnnotations::new(&s);
+        assert_eq!(a.handler(), Some("wasmtime"));
+        assert_eq!(a.entrypoint(), Some("/main.wasm"));
+        assert_eq!(a.env_file(), Some(PathBuf::from("/etc/app.env")));
+        assert_eq!(a.module(), Some("stdin"));
+        assert_eq!(a.stdin_max_bytes().unwrap(), Some(1024));
+        assert!(a.preopen_root().unwrap());
+        assert_eq!(a.nice().unwrap(), Some(-5));
+        assert_eq!(a.opt_level().unwrap(), Some(OptLevel::None));
+    }
+
+    #[test]
+    fn missing_annotations_are_unset() {
+        let s = spec(&["/app.wasm"], &[("youki.wasm.unknown", "x")]);
+        let a = WasmAnnotations::new(&s);
+        assert_eq!(a.handler(), None);
+        assert_eq!(a.entrypoint(), None);
+        assert_eq!(a.env_file(), None);
+        assert_eq!(a.module(), None);
+        assert_eq!(a.stdin_max_bytes().unwrap(), None);
+        assert!(!a.preopen_root().unwrap());
+        assert_eq!(a.nice().unwrap(), None);
+        assert_eq!(a.opt_level().unwrap(), None);
+    }
+
+    #[test]
+    fn rejects_invalid_annotation_values() {
+        let invalid = |key: &str, value: &str| spec(&["/app.wasm"], &[(key, value)]);
+        let s = invalid(STDIN_MAX_BYTES_ANNOTATION, "-1");
+        assert!(WasmAnnotations::new(&s).stdin_max_bytes().is_err());
+        let s = invalid(PREOPEN_ROOT_ANNOTATION, "yes");
+        assert!(WasmAnnotations::new(&s).preopen_root().is_err());
+        let s = invalid(OPT_LEVEL_ANNOTATION, "fast");
+        assert!(WasmAnnotations::new(&s).opt_level().is_err());
+        let s = invalid(NICE_ANNOTATION, "high");
+        let err = WasmAnnotations::new(&s).nice().unwrap_err();
+        // the error names the annotation
+        assert!(format!("{err:#}").contains(NICE_ANNOTATION), "{err:#}");
+    }
+
+    #[test]
+    fn catch_panic_reports_panics_as_errors() {
+        assert_eq!(catch_panic(|| Ok(7)).unwrap(), 7);
+        let err = catch_panic::<()>(|| bail!("trap")).unwrap_err();
+        assert_eq!(err.to_string(), "trap");
+
+        let err = catch_panic::<()>(|| panic!("static message")).unwrap_err();
+        assert_eq!(err.to_string(), "executor panicked: static message");
+        let code = 3;
+        let err = catch_panic::<()>(|| panic!("formatted {}", code)).unwrap_err();
+        assert_eq!(err.to_string(), "executor panicked: formatted 3");
+        let err = catch_panic::<()>(|| std::panic::panic_any(42)).unwrap_err();
+        assert_eq!(err.to_string(), "executor panicked: unknown cause");
+    }
+}
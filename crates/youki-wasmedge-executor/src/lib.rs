@@ -0,0 +1,145 @@
+use anyhow::{anyhow, Context, Result};
+use libcontainer::workload::{Executor, ExecutorError};
+use oci_spec::runtime::Spec;
+use std::fs;
+use std::path::{Path, PathBuf};
+use wasmedge_sdk::{
+    config::{CommonConfigOptions, ConfigBuilder, HostRegistrationConfigOptions},
+    params, Module, VmBuilder,
+};
+use youki_wasm_common::{
+    can_handle, catch_panic, ensure_core_module, env_vars, guest_args, mark_running,
+    module_from_stdin, module_in_rootfs, module_path, opt_level, preopen_dirs, preopen_root,
+    read_stdin_module, OptLevel,
+};
+
+const EXECUTOR_NAME: &str = "wasmedge";
+/// The wasmedge-sdk version the executor is built with, as resolved in
+/// Cargo.lock, see build.rs.
+pub const ENGINE_VERSION: &str = env!("ENGINE_VERSION");
+
+/// Runs WASI modules with WasmEdge. The module is taken from `args[0]` of the
+/// spec's process, or from the `youki.wasm.entrypoint` annotation, the
+/// remaining args are passed through as guest argv. `default_env` is added to
+/// the guest's environment, see `youki_wasm_common::env_vars`. WasmEdge
+/// interprets modules, so `opt_level` is only checked and otherwise ignored.
+#[derive(Default)]
+pub struct WasmEdgeExecutor {
+    default_env: Vec<(String, String)>,
+    opt_level: OptLevel,
+}
+
+impl WasmEdgeExecutor {
+    pub fn new(default_env: Vec<(String, String)>, opt_level: OptLevel) -> Self {
+        WasmEdgeExecutor {
+            default_env,
+            opt_level,
+        }
+    }
+}
+
+impl Executor for WasmEdgeExecutor {
+    fn exec(&self, spec: &Spec) -> Result<(), ExecutorError> {
+        log::info!("executing workload with wasmedge handler");
+        let res = catch_panic(|| {
+            let opt_level = opt_level(spec, self.opt_level)?;
+            if opt_level != OptLevel::default() {
+                log::warn!(
+                    "wasmedge does not support opt level {:?}, ignoring it",
+                    opt_level
+                );
+            }
+            run(spec, &self.default_env)
+        });
+        match res {
+            Ok(code) => std::process::exit(code as i32),
+            Err(err) => {
+                log::error!("wasmedge executor failed: {:#}", err);
+                Err(ExecutorError::Execution(err.into()))
+            }
+        }
+    }
+
+    fn can_handle(&self, spec: &Spec) -> bool {
+        can_handle(spec, EXECUTOR_NAME)
+    }
+
+    fn name(&self) -> &'static str {
+        EXECUTOR_NAME
+    }
+}
+
+/// Checks that the module the spec would run loads and validates in WasmEdge,
+/// without running it. Meant to be called from the host, so the module is
+/// looked up in `rootfs`. Returns the host path of the module.
+pub fn validate(spec: &Spec, rootfs: &Path) -> Result<PathBuf> {
+    let path = module_in_rootfs(spec, rootfs)?;
+    let bytes =
+        fs::read(&path).with_context(|| format!("could not read module {}", path.display()))?;
+    ensure_core_module(&bytes, &path)?;
+    Module::from_bytes(None, &bytes)?;
+    Ok(path)
+}
+
+/// Runs the module and returns the guest's WASI exit code.
+fn run(spec: &Spec, default_env: &[(String, String)]) -> Result<u32> {
+    let args = guest_args(spec);
+    let envs: Vec<String> = env_vars(spec, default_env)?
+        .into_iter()
+        .map(|(k, v)| format!("{k}={v}"))
+        .collect();
+
+    let config = ConfigBuilder::new(CommonConfigOptions::default())
+        .with_host_registration_config(HostRegistrationConfigOptions::default().wasi(true))
+        .build()?;
+    let mut vm = VmBuilder::new().with_config(config).build()?;
+    let wasi_module = vm
+        .wasi_module_mut()
+        .ok_or_else(|| anyhow!("wasi module not registered"))?;
+    let preopens = preopens(spec)?;
+    wasi_module.initialize(
+        Some(args.iter().map(|s| s as &str).collect()),
+        Some(envs.iter().map(|s| s as &str).collect()),
+        Some(preopens.iter().map(|s| s as &str).collect()),
+    );
+
+    let bytes = if module_from_stdin(spec) {
+        let bytes = read_stdin_module(spec)?;
+        ensure_core_module(&bytes, Path::new("<stdin>"))?;
+        bytes
+    } else {
+        let cmd = module_path(spec)?;
+        let bytes =
+            fs::read(cmd).with_context(|| format!("could not read module {}", cmd.display()))?;
+        ensure_core_module(&bytes, cmd)?;
+        bytes
+    };
+    let vm = vm.register_module_from_bytes("main", bytes)?;
+    mark_running();
+    vm.run_func(Some("main"), "_start", params!())?;
+    let exit_code = vm
+        .wasi_module()
+        .ok_or_else(|| anyhow!("wasi module not registered"))?
+        .exit_code();
+    Ok(exit_code)
+}
+
+/// The guest's preopens, given as `guest:host`. A directory whose path
+/// contains a `:` can't be expressed that way and is left out.
+fn preopens(spec: &Spec) -> Result<Vec<String>> {
+    let mut preopens = Vec::new();
+    if preopen_root(spec)? {
+        log::debug!("preopening the container root as /");
+        preopens.push("/:/".to_string());
+    }
+    for dir in preopen_dirs(spec) {
+        let dir = dir.to_string_lossy();
+        if dir.contains(':') {
+            log::warn!("not preopening {}: wasmedge can't map paths with ':'", dir);
+            continue;
+        }
+        log::debug!("preopening {}", dir);
+        preopens.push(format!("{dir}:{dir}"));
+    }
+    Ok(preopens)
+}
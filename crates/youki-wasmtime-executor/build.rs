@@ -0,0 +1,7 @@
+//! Records the wasmtime version resolved in Cargo.lock as `ENGINE_VERSION`.
+
+include!("../build/engine_version.rs");
+
+fn main() {
+    record_engine_version("wasmtime");
+}
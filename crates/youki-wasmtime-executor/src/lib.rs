@@ -0,0 +1,293 @@
+use anyhow::{Context, Result};
+use libcontainer::workload::{Executor, ExecutorError};
+use oci_spec::runtime::Spec;
+use std::fs;
+use std::path::{Path, PathBuf};
+use wasmtime::{Config, Engine, Linker, Module, Store, StoreLimits, StoreLimitsBuilder, Trap};
+use wasmtime_wasi::{ambient_authority, Dir, I32Exit, WasiCtx, WasiCtxBuilder};
+use youki_wasm_common::{
+    can_handle, catch_panic, ensure_core_module, env_vars, guest_args, mark_running,
+    module_from_stdin, module_in_rootfs, module_path, opt_level, preopen_dirs, preopen_root,
+    read_stdin_module, OptLevel,
+};
+
+const EXECUTOR_NAME: &str = "wasmtime";
+/// The wasmtime version the executor is built with, as resolved in
+/// Cargo.lock, see build.rs.
+pub const ENGINE_VERSION: &str = env!("ENGINE_VERSION");
+/// Exit code reported when the guest traps, mirroring a SIGKILL'd process.
+const TRAP_EXIT_CODE: i32 = 137;
+const WASM_PAGE_SIZE: u64 = 64 * 1024;
+
+/// Data of the guest's store.
+struct Host {
+    wasi: WasiCtx,
+    limits: StoreLimits,
+}
+
+/// Runs WASI modules with wasmtime. The module is taken from `args[0]` of the
+/// spec's process, or from the `youki.wasm.entrypoint` annotation, the
+/// remaining args are passed through as guest argv.
+/// `engine_features` enables wasm proposals on top of wasmtime's defaults.
+/// `default_env` is added to the guest's environment, see
+/// `youki_wasm_common::env_vars`. `opt_level` is used for workloads that
+/// don't pick one with the `youki.wasm.opt_level` annotation.
+#[derive(Default)]
+pub struct WasmtimeExecutor {
+    engine_features: Vec<String>,
+    default_env: Vec<(String, String)>,
+    opt_level: OptLevel,
+}
+
+impl WasmtimeExecutor {
+    pub fn new(
+        engine_features: Vec<String>,
+        default_env: Vec<(String, String)>,
+        opt_level: OptLevel,
+    ) -> Self {
+        WasmtimeExecutor {
+            engine_features,
+            default_env,
+            opt_level,
+        }
+    }
+}
+
+impl Executor for WasmtimeExecutor {
+    fn exec(&self, spec: &Spec) -> Result<(), ExecutorError> {
+        log::info!("executing workload with wasmtime handler");
+        // Always exit with the guest's status once the module ran, so the
+        // container reports the code the guest asked for.
+        let res = catch_panic(|| {
+            let opt_level = opt_level(spec, self.opt_level)?;
+            run(spec, &self.engine_features, &self.default_env, opt_level)
+        });
+        match res {
+            Ok(()) => std::process::exit(0),
+            Err(err) => {
+                if let Some(exit) = err.downcast_ref::<I32Exit>() {
+                    std::process::exit(exit.0);
+                }
+                log::error!("wasmtime executor failed: {:#}", err);
+                if err.downcast_ref::<Trap>().is_some() {
+                    std::process::exit(TRAP_EXIT_CODE);
+                }
+                Err(ExecutorError::Execution(err.into()))
+            }
+        }
+    }
+
+    fn can_handle(&self, spec: &Spec) -> bool {
+        can_handle(spec, EXECUTOR_NAME)
+    }
+
+    fn name(&self) -> &'static str {
+        EXECUTOR_NAME
+    }
+}
+
+/// Checks that the module the spec would run compiles, without running it.
+/// Meant to be called from the host, so the module is looked up in `rootfs`.
+/// Returns the host path of the module.
+pub fn validate(spec: &Spec, rootfs: &Path, engine_features: &[String]) -> Result<PathBuf> {
+    let path = module_in_rootfs(spec, rootfs)?;
+    let bytes =
+        fs::read(&path).with_context(|| format!("could not read module {}", path.display()))?;
+    ensure_core_module(&bytes, &path)?;
+    let config = engine_config(engine_features, OptLevel::default());
+    Module::validate(&Engine::new(&config)?, &bytes)?;
+    Ok(path)
+}
+
+fn run(
+    spec: &Spec,
+    engine_features: &[String],
+    default_env: &[(String, String)],
+    opt_level: OptLevel,
+) -> Result<()> {
+    let args = guest_args(spec);
+    let envs = env_vars(spec, default_env)?;
+
+    let engine = Engine::new(&engine_config(engine_features, opt_level))?;
+    let module = if module_from_stdin(spec) {
+        let bytes = read_stdin_module(spec)?;
+        ensure_core_module(&bytes, Path::new("<stdin>"))?;
+        Module::new(&engine, &bytes).context("could not load wasm module from stdin")?
+    } else {
+        let cmd = module_path(spec)?;
+        let bytes =
+            fs::read(cmd).with_context(|| format!("could not read module {}", cmd.display()))?;
+        ensure_core_module(&bytes, cmd)?;
+        Module::new(&engine, &bytes)
+            .with_context(|| format!("could not load wasm module {}", cmd.display()))?
+    };
+
+    let mut linker: Linker<Host> = Linker::new(&engine);
+    wasmtime_wasi::add_to_linker(&mut linker, |h| &mut h.wasi)?;
+    let mut builder = WasiCtxBuilder::new()
+        .inherit_stdio()
+        .args(&args)?
+        .envs(&envs)?;
+    if preopen_root(spec)? {
+        log::debug!("preopening the container root as /");
+        let fd = Dir::open_ambient_dir("/", ambient_authority())
+            .context("could not open the container root")?;
+        builder = builder.preopened_dir(fd, "/")?;
+    }
+    for dir in preopen_dirs(spec) {
+        log::debug!("preopening {}", dir.display());
+        let fd = Dir::open_ambient_dir(&dir, ambient_authority())
+            .with_context(|| format!("could not open mount {}", dir.display()))?;
+        builder = builder.preopened_dir(fd, &dir)?;
+    }
+    // WASI has no notion of a working directory, relative guest paths are
+    // resolved against a "." preopen instead.
+    if let Some(cwd) = working_dir(spec) {
+        log::debug!("using {} as working directory", cwd.display());
+        let fd = Dir::open_ambient_dir(cwd, ambient_authority())
+            .with_context(|| format!("could not open working directory {}", cwd.display()))?;
+        builder = builder.preopened_dir(fd, ".")?;
+    }
+    let mut limits = StoreLimitsBuilder::new();
+    if let Some(size) = memory_limit(spec) {
+        log::debug!("limiting guest memory to {} bytes", size);
+        limits = limits.memory_size(size);
+    }
+    let host = Host {
+        wasi: builder.build(),
+        limits: limits.build(),
+    };
+    let mut store = Store::new(&engine, host);
+    store.limiter(|h| &mut h.limits);
+
+    let instance = linker.instantiate(&mut store, &module)?;
+    let start = instance
+        .get_typed_func::<(), ()>(&mut store, "_start")
+        .context("module does not export _start")?;
+    mark_running();
+    start.call(&mut store, ())
+}
+
+/// An engine config with the named wasm proposals enabled, compiling with
+/// `opt_level`. Unknown names are logged and ignored so a typo doesn't keep
+/// the workload from running.
+fn engine_config(features: &[String], opt_level: OptLevel) -> Config {
+    let mut config = Config::new();
+    config.cranelift_opt_level(cranelift_opt_level(opt_level));
+    for feature in features {
+        match feature.as_str() {
+            "simd" => config.wasm_simd(true),
+            "relaxed-simd" => config.wasm_relaxed_simd(true),
+            "threads" => config.wasm_threads(true),
+            "bulk-memory" => config.wasm_bulk_memory(true),
+            "reference-types" => config.wasm_reference_types(true),
+            "multi-value" => config.wasm_multi_value(true),
+            "multi-memory" => config.wasm_multi_memory(true),
+            "memory64" => config.wasm_memory64(true),
+            _ => {
+                log::warn!("ignoring unknown engine feature {:?}", feature);
+                continue;
+            }
+        };
+    }
+    config
+}
+
+fn cranelift_opt_level(opt_level: OptLevel) -> wasmtime::OptLevel {
+    match opt_level {
+        OptLevel::None => wasmtime::OptLevel::None,
+        OptLevel::Speed => wasmtime::OptLevel::Speed,
+        OptLevel::SpeedAndSize => wasmtime::OptLevel::SpeedAndSize,
+    }
+}
+
+/// The cgroup memory limit rounded up to whole wasm pages, so that a limit
+/// below one page still leaves the guest a page. Enforcing it on the guest's
+/// linear memory makes an oversized `memory.grow` fail inside the guest
+/// instead of getting the whole process OOM killed.
+fn memory_limit(spec: &Spec) -> Option<usize> {
+    let limit = spec
+        .linux()
+        .as_ref()?
+        .resources()
+        .as_ref()?
+        .memory()
+        .as_ref()?
+        .limit()?;
+    // -1 means unlimited
+    let limit = u64::try_from(limit).ok().filter(|l| *l > 0)?;
+    usize::try_from(limit.div_ceil(WASM_PAGE_SIZE) * WASM_PAGE_SIZE).ok()
+}
+
+/// The spec's `process.cwd`, unless it is the root which needs no preopen.
+fn working_dir(spec: &Spec) -> Option<&Path> {
+    spec.process()
+        .as_ref()
+        .map(|p| p.cwd().as_path())
+        .filter(|cwd| *cwd != Path::new("/"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use oci_spec::runtime::{LinuxMemoryBuilder, LinuxResourcesBuilder};
+
+    fn with_memory_limit(limit: i64) -> Spec {
+        let mut spec = Spec::default();
+        let memory = LinuxMemoryBuilder::default().limit(limit).build().unwrap();
+        let resources = LinuxResourcesBuilder::default()
+            .memory(memory)
+            .build()
+            .unwrap();
+        let mut linux = spec.linux().clone().unwrap_or_default();
+        linux.set_resources(Some(resources));
+        spec.set_linux(Some(linux));
+        spec
+    }
+
+    #[test]
+    fn memory_limit_rounds_up_to_whole_pages() {
+        let page = WASM_PAGE_SIZE as usize;
+        assert_eq!(memory_limit(&with_memory_limit(1)), Some(page));
+        assert_eq!(memory_limit(&with_memory_limit(64 * 1024)), Some(page));
+        assert_eq!(
+            memory_limit(&with_memory_limit(64 * 1024 + 1)),
+            Some(2 * page)
+        );
+        // unlimited
+        assert_eq!(memory_limit(&with_memory_limit(-1)), None);
+        assert_eq!(memory_limit(&with_memory_limit(0)), None);
+        assert_eq!(memory_limit(&Spec::default()), None);
+    }
+
+    #[test]
+    fn applies_opt_level() {
+        let levels = [
+            (OptLevel::None, wasmtime::OptLevel::None),
+            (OptLevel::Speed, wasmtime::OptLevel::Speed),
+            (OptLevel::SpeedAndSize, wasmtime::OptLevel::SpeedAndSize),
+        ];
+        for (level, cranelift) in levels {
+            assert_eq!(cranelift_opt_level(level), cranelift);
+            assert!(Engine::new(&engine_config(&[], level)).is_ok());
+        }
+    }
+
+    #[test]
+    fn engine_config_enables_requested_features() {
+        let wat = "(module (memory 1) (memory 1))";
+
+        let engine = Engine::new(&engine_config(&[], OptLevel::default())).unwrap();
+        assert!(Module::new(&engine, wat).is_err());
+
+        let features = ["multi-memory".to_string()];
+        let engine = Engine::new(&engine_config(&features, OptLevel::default())).unwrap();
+        assert!(Module::new(&engine, wat).is_ok());
+    }
+
+    #[test]
+    fn engine_config_ignores_unknown_features() {
+        let features = ["no-such-feature".to_string(), "simd".to_string()];
+        assert!(Engine::new(&engine_config(&features, OptLevel::default())).is_ok());
+    }
+}
@@ -0,0 +1,224 @@
+//! Terminal support for specs with `process.terminal` set. libcontainer
+//! allocates a PTY inside the container and sends its master over a console
+//! socket. The shim then copies between the master and containerd's stdio
+//! FIFOs, like the runc shim does. Output of a container started detached
+//! is buffered until a client attaches to its stdout.
+
+use anyhow::{anyhow, bail, Context, Result};
+use nix::errno::Errno;
+use nix::fcntl::{fcntl, FcntlArg, OFlag};
+use nix::poll::{poll, PollFd, PollFlags};
+use nix::sys::socket::{recvmsg, ControlMessageOwned, MsgFlags};
+use nix::unistd::{read, write};
+use std::collections::VecDeque;
+use std::fs::{self, File};
+use std::io::{self, IoSliceMut};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::net::UnixListener;
+use std::path::{Path, PathBuf};
+use std::thread;
+
+/// How long to wait for the container to send the PTY master.
+const RECEIVE_TIMEOUT_MS: i32 = 10_000;
+/// How much terminal output nobody read yet is kept by default.
+pub const DEFAULT_BUFFER_BYTES: usize = 1024 * 1024;
+/// How long output buffered when the container exited waits for a reader.
+const FLUSH_TIMEOUT_MS: i32 = 5_000;
+
+/// A socket for the container to send its PTY master over. The socket file
+/// is removed on drop.
+pub struct ConsoleSocket {
+    listener: UnixListener,
+    path: PathBuf,
+}
+
+impl ConsoleSocket {
+    /// Listens on `path`, replacing a socket left behind by a previous shim.
+    pub fn new(path: PathBuf) -> Result<Self> {
+        let _ = fs::remove_file(&path);
+        let listener = UnixListener::bind(&path)
+            .with_context(|| format!("could not create console socket {}", path.display()))?;
+        Ok(ConsoleSocket { listener, path })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Receives the PTY master sent by the container's init process.
+    pub fn receive_master(&self) -> Result<OwnedFd> {
+        let mut fds = [PollFd::new(self.listener.as_raw_fd(), PollFlags::POLLIN)];
+        if poll(&mut fds, RECEIVE_TIMEOUT_MS)? == 0 {
+            bail!("container did not connect to the console socket");
+        }
+        let (stream, _) = self.listener.accept()?;
+
+        let mut buf = [0u8; 4096];
+        let mut iov = [IoSliceMut::new(&mut buf)];
+        let mut cmsg = nix::cmsg_space!([RawFd; 1]);
+        let msg = recvmsg::<()>(
+            stream.as_raw_fd(),
+            &mut iov,
+            Some(&mut cmsg),
+            MsgFlags::empty(),
+        )?;
+        for cmsg in msg.cmsgs() {
+            if let ControlMessageOwned::ScmRights(fds) = cmsg {
+                if let Some(fd) = fds.first() {
+                    // Safety: the fd was just received and is owned by nobody else.
+                    return Ok(unsafe { OwnedFd::from_raw_fd(*fd) });
+                }
+            }
+        }
+        Err(anyhow!("no PTY master received on the console socket"))
+    }
+}
+
+impl Drop for ConsoleSocket {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Copies `stdin` to the PTY master and the master to `stdout` until either
+/// side is closed. A PTY merges stdout and stderr, so there is no stderr.
+/// Output is kept in memory up to `buffer_bytes` while nobody reads `stdout`,
+/// see `copy_buffered`.
+pub fn forward(
+    master: OwnedFd,
+    stdin: Option<OwnedFd>,
+    stdout: Option<OwnedFd>,
+    buffer_bytes: usize,
+) -> Result<()> {
+    if let Some(stdin) = stdin {
+        let mut master = File::from(master.try_clone()?);
+        thread::spawn(move || {
+            let _ = io::copy(&mut File::from(stdin), &mut master);
+        });
+    }
+    thread::spawn(move || {
+        if let Some(stdout) = stdout {
+            match copy_buffered(&master, &stdout, buffer_bytes) {
+                Ok(()) => return,
+                Err(err) => log::warn!("failed to forward terminal output: {:#}", err),
+            }
+        }
+        // keep draining so the guest doesn't block on a full PTY
+        let _ = io::copy(&mut File::from(master), &mut io::sink());
+    });
+    Ok(())
+}
+
+/// Copies the PTY master to `out` without ever blocking on `out`. Until a
+/// client reads `out`, e.g. when the container was started detached and is
+/// attached to later, the output is kept in memory, dropping the oldest
+/// beyond `max_buffered` bytes. The guest never blocks on a full PTY.
+fn copy_buffered(master: &OwnedFd, out: &OwnedFd, max_buffered: usize) -> Result<()> {
+    let flags = OFlag::from_bits_truncate(fcntl(out.as_raw_fd(), FcntlArg::F_GETFL)?);
+    fcntl(
+        out.as_raw_fd(),
+        FcntlArg::F_SETFL(flags | OFlag::O_NONBLOCK),
+    )?;
+    let mut pending = VecDeque::<u8>::new();
+    let mut buf = [0u8; 4096];
+    let mut open = true;
+    let mut dropped = false;
+    while open || !pending.is_empty() {
+        let mut fds = Vec::with_capacity(2);
+        if open {
+            fds.push(PollFd::new(master.as_raw_fd(), PollFlags::POLLIN));
+        }
+        if !pending.is_empty() {
+            fds.push(PollFd::new(out.as_raw_fd(), PollFlags::POLLOUT));
+        }
+        match poll(&mut fds, if open { -1 } else { FLUSH_TIMEOUT_MS }) {
+            Ok(0) => {
+                log::debug!(
+                    "dropping {} bytes of terminal output nobody read",
+                    pending.len()
+                );
+                return Ok(());
+            }
+            Ok(_) => {}
+            Err(Errno::EINTR) => continue,
+            Err(err) => return Err(err.into()),
+        }
+        if open && fds[0].revents().is_some_and(|r| !r.is_empty()) {
+            match read(master.as_raw_fd(), &mut buf) {
+                // reading the master fails with EIO once the container exited
+                Ok(0) | Err(Errno::EIO) => open = false,
+                Ok(n) => {
+                    pending.extend(&buf[..n]);
+                    if pending.len() > max_buffered {
+                        pending.drain(..pending.len() - max_buffered);
+                        if !dropped {
+                            log::warn!("terminal output is not read, dropping the oldest");
+                            dropped = true;
+                        }
+                    }
+                }
+                Err(Errno::EINTR | Errno::EAGAIN) => {}
+                Err(err) => return Err(err.into()),
+            }
+        }
+        if !pending.is_empty() {
+            match write(out.as_raw_fd(), pending.as_slices().0) {
+                Ok(n) => {
+                    pending.drain(..n);
+                }
+                Err(Errno::EINTR | Errno::EAGAIN) => {}
+                Err(err) => return Err(err.into()),
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nix::sys::stat::Mode;
+    use std::fs::OpenOptions;
+    use std::io::{Read, Write};
+    use std::os::unix::net::UnixStream;
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    #[test]
+    fn buffers_output_until_attached() {
+        let dir = tempfile::tempdir().unwrap();
+        let fifo = dir.path().join("stdout");
+        nix::unistd::mkfifo(&fifo, Mode::S_IRUSR | Mode::S_IWUSR).unwrap();
+        // opened like the shim opens stdout, nobody reads it yet
+        let stdout = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&fifo)
+            .unwrap();
+        // a socket stands in for the PTY master
+        let (master, mut guest) = UnixStream::pair().unwrap();
+        forward(
+            master.into(),
+            None,
+            Some(stdout.into()),
+            DEFAULT_BUFFER_BYTES,
+        )
+        .unwrap();
+
+        // more than the FIFO holds, so it can only be written while buffered
+        let output = vec![b'x'; 256 * 1024];
+        let len = output.len();
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            let res = guest.write_all(&output);
+            let _ = tx.send(res.is_ok());
+        });
+        let written = rx.recv_timeout(Duration::from_secs(10));
+        assert_eq!(written, Ok(true), "guest blocked on unread output");
+
+        let mut attached = File::open(&fifo).unwrap();
+        let mut received = vec![0u8; len];
+        attached.read_exact(&mut received).unwrap();
+        assert!(received.iter().all(|b| *b == b'x'));
+    }
+}
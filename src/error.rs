@@ -0,0 +1,210 @@
+//! Errors of the shim. They keep what went wrong apart until the `Instance`
+//! boundary, where they are mapped onto containerd's error classes.
+
+use containerd_shim_wasm::sandbox::Error;
+use nix::errno::Errno;
+use std::fmt;
+use std::io::ErrorKind;
+
+#[derive(Debug)]
+pub enum ShimError {
+    /// The container or one of its resources doesn't exist.
+    NotFound(String),
+    AlreadyExists(String),
+    /// The bundle's spec or options are invalid.
+    InvalidSpec(String),
+    /// Reading or changing the container's cgroups failed.
+    Cgroup(String),
+    Io(std::io::Error),
+    /// libcontainer or a wasm engine failed to run the container.
+    Engine(String),
+}
+
+impl fmt::Display for ShimError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ShimError::NotFound(msg) => write!(f, "not found: {}", msg),
+            ShimError::AlreadyExists(msg) => write!(f, "already exists: {}", msg),
+            ShimError::InvalidSpec(msg) => write!(f, "invalid spec: {}", msg),
+            ShimError::Cgroup(msg) => write!(f, "cgroup error: {}", msg),
+            ShimError::Io(err) => write!(f, "io error: {}", err),
+            ShimError::Engine(msg) => write!(f, "engine error: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for ShimError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ShimError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for ShimError {
+    fn from(err: std::io::Error) -> Self {
+        ShimError::Io(err)
+    }
+}
+
+impl From<oci_spec::OciSpecError> for ShimError {
+    fn from(err: oci_spec::OciSpecError) -> Self {
+        ShimError::InvalidSpec(err.to_string())
+    }
+}
+
+/// Classifies a failure by the first cause in its chain that tells what went
+/// wrong, e.g. an invalid spec or a permission problem. The message keeps the
+/// whole chain. Unrecognized failures are engine errors.
+impl From<anyhow::Error> for ShimError {
+    fn from(err: anyhow::Error) -> Self {
+        let msg = format!("{:#}", err);
+        for cause in err.chain() {
+            if cause.downcast_ref::<oci_spec::OciSpecError>().is_some() {
+                return ShimError::InvalidSpec(msg);
+            }
+            let kind = match cause.downcast_ref::<Errno>() {
+                Some(errno) => Some(std::io::Error::from(*errno).kind()),
+                None => cause.downcast_ref::<std::io::Error>().map(|e| e.kind()),
+            };
+            if let Some(kind) = kind.filter(|kind| io_class(*kind).is_some()) {
+                return ShimError::Io(std::io::Error::new(kind, msg));
+            }
+        }
+        ShimError::Engine(msg)
+    }
+}
+
+/// The containerd error class of an io failure of `kind`, `None` for kinds
+/// that say nothing about the cause.
+fn io_class(kind: ErrorKind) -> Option<fn(String) -> Error> {
+    match kind {
+        ErrorKind::NotFound => Some(Error::NotFound),
+        // retrying won't help until the host or the spec changes
+        ErrorKind::PermissionDenied | ErrorKind::OutOfMemory | ErrorKind::StorageFull => {
+            Some(Error::FailedPrecondition)
+        }
+        ErrorKind::InvalidInput | ErrorKind::InvalidData => Some(Error::InvalidArgument),
+        _ => None,
+    }
+}
+
+impl From<ShimError> for Error {
+    fn from(err: ShimError) -> Self {
+        let msg = err.to_string();
+        match err {
+            ShimError::NotFound(_) => Error::NotFound(msg),
+            ShimError::AlreadyExists(_) => Error::AlreadyExists(msg),
+            ShimError::InvalidSpec(_) => Error::InvalidArgument(msg),
+            ShimError::Cgroup(_) | ShimError::Engine(_) => Error::Others(msg),
+            ShimError::Io(err) => io_class(err.kind()).unwrap_or(Error::Others)(msg),
+        }
+    }
+}
+
+/// A copy of `err` with `context` prepended to its message, in the same
+/// containerd error class. Classes the shim doesn't produce become `Others`.
+pub fn with_context(err: &Error, context: &str) -> Error {
+    let msg = format!("{}: {}", context, err);
+    match err {
+        Error::NotFound(_) => Error::NotFound(msg),
+        Error::AlreadyExists(_) => Error::AlreadyExists(msg),
+        Error::InvalidArgument(_) => Error::InvalidArgument(msg),
+        Error::FailedPrecondition(_) => Error::FailedPrecondition(msg),
+        _ => Error::Others(msg),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::{anyhow, Context};
+
+    fn io(kind: ErrorKind) -> std::io::Error {
+        std::io::Error::new(kind, "io failure")
+    }
+
+    #[test]
+    fn maps_variants_to_containerd_classes() {
+        let classes = [
+            (ShimError::NotFound("x".into()), "NotFound"),
+            (ShimError::AlreadyExists("x".into()), "AlreadyExists"),
+            (ShimError::InvalidSpec("x".into()), "InvalidArgument"),
+            (ShimError::Cgroup("x".into()), "Others"),
+            (ShimError::Engine("x".into()), "Others"),
+            (ShimError::Io(io(ErrorKind::NotFound)), "NotFound"),
+            (
+                ShimError::Io(io(ErrorKind::PermissionDenied)),
+                "FailedPrecondition",
+            ),
+            (
+                ShimError::Io(io(ErrorKind::StorageFull)),
+                "FailedPrecondition",
+            ),
+            (
+                ShimError::Io(io(ErrorKind::InvalidInput)),
+                "InvalidArgument",
+            ),
+            // transient, like is_transient says
+            (ShimError::Io(io(ErrorKind::WouldBlock)), "Others"),
+            (ShimError::Io(io(ErrorKind::Other)), "Others"),
+        ];
+        for (err, class) in classes {
+            let msg = err.to_string();
+            let mapped = Error::from(err);
+            let (got, got_msg) = match &mapped {
+                Error::NotFound(m) => ("NotFound", m),
+                Error::AlreadyExists(m) => ("AlreadyExists", m),
+                Error::InvalidArgument(m) => ("InvalidArgument", m),
+                Error::FailedPrecondition(m) => ("FailedPrecondition", m),
+                Error::Others(m) => ("Others", m),
+                other => panic!("unexpected class {:?}", other),
+            };
+            assert_eq!(got, class, "{}", msg);
+            assert_eq!(got_msg, &msg);
+        }
+    }
+
+    #[test]
+    fn classifies_anyhow_chains_by_first_known_cause() {
+        let err = anyhow::Error::from(io(ErrorKind::PermissionDenied)).context("could not open");
+        match ShimError::from(err) {
+            ShimError::Io(err) => {
+                assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+                // the message keeps the whole chain
+                assert_eq!(err.to_string(), "could not open: io failure");
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+
+        let err = Err::<(), _>(Errno::ENOENT)
+            .context("inner")
+            .context("outer")
+            .unwrap_err();
+        assert!(
+            matches!(ShimError::from(err), ShimError::Io(e) if e.kind() == ErrorKind::NotFound)
+        );
+
+        let err = anyhow::Error::from(oci_spec::OciSpecError::Other("bad".into())).context("load");
+        assert!(matches!(ShimError::from(err), ShimError::InvalidSpec(_)));
+
+        // causes that tell nothing fall through to an engine error
+        let err = anyhow::Error::from(io(ErrorKind::Other)).context("run");
+        assert!(matches!(ShimError::from(err), ShimError::Engine(_)));
+        assert!(matches!(
+            ShimError::from(anyhow!("trap")),
+            ShimError::Engine(_)
+        ));
+    }
+
+    #[test]
+    fn with_context_keeps_the_class() {
+        let err = with_context(&Error::NotFound("bundle".into()), "failed to initialize");
+        assert!(matches!(err, Error::NotFound(msg) if msg.starts_with("failed to initialize: ")));
+        let err = with_context(&Error::InvalidArgument("spec".into()), "init");
+        assert!(matches!(err, Error::InvalidArgument(_)));
+        let err = with_context(&Error::Others("engine".into()), "init");
+        assert!(matches!(err, Error::Others(_)));
+    }
+}
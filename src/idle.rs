@@ -0,0 +1,69 @@
+//! Optional idle shutdown. With `YOUKI_SHIM_IDLE_TIMEOUT_SECS` set, the shim
+//! exits once it has managed no container for that long, so that containerd
+//! can reap it. `0` or an unset variable disables the timeout.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const IDLE_TIMEOUT_ENV: &str = "YOUKI_SHIM_IDLE_TIMEOUT_SECS";
+
+static ACTIVE: AtomicUsize = AtomicUsize::new(0);
+static LAST_ACTIVITY: Mutex<Option<Instant>> = Mutex::new(None);
+
+/// Marks a container instance as managed by the shim for as long as it lives.
+pub struct Activity(());
+
+impl Activity {
+    pub fn new() -> Self {
+        ACTIVE.fetch_add(1, Ordering::SeqCst);
+        touch();
+        Activity(())
+    }
+}
+
+impl Drop for Activity {
+    fn drop(&mut self) {
+        ACTIVE.fetch_sub(1, Ordering::SeqCst);
+        touch();
+    }
+}
+
+/// Resets the idle timer.
+pub fn touch() {
+    *LAST_ACTIVITY.lock().unwrap() = Some(Instant::now());
+}
+
+/// Starts the thread exiting the shim after the configured idle timeout.
+pub fn spawn_watchdog() {
+    let timeout = match std::env::var(IDLE_TIMEOUT_ENV) {
+        Ok(secs) => match secs.parse::<u64>() {
+            Ok(secs) => Duration::from_secs(secs),
+            Err(err) => {
+                log::warn!("ignoring invalid {}={:?}: {}", IDLE_TIMEOUT_ENV, secs, err);
+                return;
+            }
+        },
+        Err(_) => return,
+    };
+    if timeout.is_zero() {
+        return;
+    }
+    touch();
+    thread::spawn(move || loop {
+        thread::sleep(timeout.min(Duration::from_secs(1)));
+        if ACTIVE.load(Ordering::SeqCst) > 0 {
+            continue;
+        }
+        let idle = LAST_ACTIVITY
+            .lock()
+            .unwrap()
+            .map(|at| at.elapsed())
+            .unwrap_or_default();
+        if idle >= timeout {
+            log::info!(">>> Shim idle for {:?}, exiting", idle);
+            std::process::exit(0);
+        }
+    });
+}
@@ -0,0 +1,126 @@
+//! Optional structured logging for the shim. Setting
+//! `YOUKI_SHIM_LOG_FORMAT=json` replaces containerd-shim's plain text logger
+//! with one that emits every record as a JSON object.
+//! `YOUKI_SHIM_DEBUG=1` raises the level to trace and has the shim log the
+//! resolved spec of each container it builds.
+
+use chrono::Utc;
+use log::{LevelFilter, Log, Metadata, Record};
+use serde::Serialize;
+use std::cell::RefCell;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+const LOG_FORMAT_ENV: &str = "YOUKI_SHIM_LOG_FORMAT";
+const DEBUG_ENV: &str = "YOUKI_SHIM_DEBUG";
+
+static DEBUG: AtomicBool = AtomicBool::new(false);
+
+thread_local! {
+    static CONTAINER_ID: RefCell<Option<String>> = RefCell::new(None);
+}
+
+/// Attaches the container id to the records logged from the current thread
+/// until the returned guard is dropped.
+pub fn container_context(id: &str) -> ContainerContext {
+    let prev = CONTAINER_ID.with(|c| c.replace(Some(id.to_string())));
+    ContainerContext { prev }
+}
+
+pub struct ContainerContext {
+    prev: Option<String>,
+}
+
+impl Drop for ContainerContext {
+    fn drop(&mut self) {
+        CONTAINER_ID.with(|c| *c.borrow_mut() = self.prev.take());
+    }
+}
+
+#[derive(Serialize)]
+struct Entry<'a> {
+    level: &'a str,
+    msg: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    container_id: Option<String>,
+    timestamp: String,
+}
+
+struct JsonLogger {
+    out: Mutex<Box<dyn Write + Send>>,
+}
+
+impl Log for JsonLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let entry = Entry {
+            level: record.level().as_str(),
+            msg: record.args().to_string(),
+            container_id: CONTAINER_ID.with(|c| c.borrow().clone()),
+            timestamp: Utc::now().to_rfc3339(),
+        };
+        if let Ok(line) = serde_json::to_string(&entry) {
+            let mut out = self.out.lock().unwrap();
+            let _ = writeln!(out, "{}", line);
+        }
+    }
+
+    fn flush(&self) {
+        let _ = self.out.lock().unwrap().flush();
+    }
+}
+
+/// Whether debugging was requested with `YOUKI_SHIM_DEBUG`.
+pub fn debug() -> bool {
+    DEBUG.load(Ordering::Relaxed)
+}
+
+/// Raises the log level to trace when debugging. containerd-shim sets the
+/// level while setting up its logger, so this has to run after that too.
+pub fn apply_debug_level() {
+    if debug() {
+        log::set_max_level(LevelFilter::Trace);
+    }
+}
+
+/// Installs the JSON logger if it was requested through the environment.
+/// Returns whether it did, in which case containerd-shim must not set up its
+/// own logger. Only the `serving` shim logs to containerd's log FIFO.
+pub fn init(serving: bool) -> bool {
+    // Not a flag: containerd already passes `-debug` to its shims whenever it
+    // runs at debug level itself.
+    let debug = std::env::var(DEBUG_ENV).is_ok_and(|v| v == "1" || v.eq_ignore_ascii_case("true"));
+    DEBUG.store(debug, Ordering::Relaxed);
+
+    let json = std::env::var(LOG_FORMAT_ENV)
+        .map(|f| f.eq_ignore_ascii_case("json"))
+        .unwrap_or(false);
+    if !json {
+        return false;
+    }
+    // Like containerd-shim's logger, write to the "log" FIFO containerd
+    // creates in the bundle. Nobody reads it during the `start` and `delete`
+    // actions and opening it would block forever, so they log to stderr.
+    let fifo = serving.then(|| OpenOptions::new().write(true).open("log").ok());
+    let out: Box<dyn Write + Send> = match fifo.flatten() {
+        Some(f) => Box::new(f),
+        None => Box::new(std::io::stderr()),
+    };
+    let logger = JsonLogger {
+        out: Mutex::new(out),
+    };
+    if log::set_boxed_logger(Box::new(logger)).is_err() {
+        return false;
+    }
+    log::set_max_level(LevelFilter::Info);
+    apply_debug_level();
+    true
+}
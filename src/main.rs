@@ -1,14 +1,20 @@
-use anyhow::{bail, Context, Result};
-use nix::unistd::{dup, dup2};
+use anyhow::{anyhow, bail, Context, Result};
+use clap::Parser;
+use nix::unistd::{close, dup, dup2, Pid};
+use oci_spec::runtime::{LinuxNamespaceType, LinuxResources, Spec};
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::ffi::OsStr;
 use std::fs::OpenOptions;
-use std::os::fd::{IntoRawFd, RawFd};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::fs::{FileTypeExt, OpenOptionsExt};
 use std::thread;
 use std::{
     fs::{self, File},
-    io::{ErrorKind, Read},
-    path::{Path, PathBuf},
-    sync::{Arc, Condvar, Mutex},
+    io::{ErrorKind, Write},
+    path::{Component, Path, PathBuf},
+    sync::{mpsc, Arc, Condvar, Mutex},
+    time::{Duration, Instant, SystemTime},
 };
 
 use chrono::{DateTime, Utc};
@@ -17,23 +23,159 @@ use containerd_shim_wasm::sandbox::{
     instance::{InstanceConfig, Wait},
     EngineGetter, Error, Instance, ShimCli,
 };
-use libc::{SIGINT, SIGKILL, STDERR_FILENO, STDIN_FILENO, STDOUT_FILENO};
+use libc::{SIGKILL, STDERR_FILENO, STDIN_FILENO, STDOUT_FILENO};
 use libcontainer::{
-    container::builder::ContainerBuilder, syscall::syscall::create_syscall,
-    workload::default::DefaultExecutor,
+    container::builder::ContainerBuilder,
+    syscall::{syscall::create_syscall, Syscall},
+    workload::{default::DefaultExecutor, Executor},
 };
 use log::error;
 use nix::errno::Errno;
+use nix::fcntl::{fcntl, FcntlArg, OFlag};
+use nix::poll::{poll, PollFd, PollFlags};
 use nix::sys::wait::{waitid, Id as WaitID, WaitPidFlag, WaitStatus};
+use youki_wasm_common::{OptLevel, WasmAnnotations};
+#[cfg(feature = "wasmedge")]
+use youki_wasmedge_executor::WasmEdgeExecutor;
+use youki_wasmtime_executor::WasmtimeExecutor;
+
+use error::ShimError;
 
 use libcontainer::container::{Container, ContainerStatus};
 use libcontainer::signal::Signal;
 
+mod console;
+mod error;
+mod idle;
+mod logging;
+mod metrics;
+mod validate;
+mod version;
+
 type ExitCode = Arc<(Mutex<Option<(u32, DateTime<Utc>)>>, Condvar)>;
 static DEFAULT_CONTAINER_ROOT_DIR: &str = "/run/containerd/youki";
+static DEFAULT_NAMESPACE: &str = "default";
+/// Overrides `DEFAULT_CONTAINER_ROOT_DIR` for all containers of the shim.
+const ROOT_ENV: &str = "YOUKI_SHIM_ROOT";
+const DEFAULT_STOP_TIMEOUT_SECS: u64 = 10;
+const DEFAULT_START_TIMEOUT_SECS: u64 = 30;
+const DEFAULT_START_RETRIES: u32 = 3;
+const START_RETRY_DELAY: Duration = Duration::from_millis(100);
+/// How often a start checks whether the workload runs yet.
+const START_POLL_INTERVAL: Duration = Duration::from_millis(10);
+/// How long an aborted start waits for libcontainer's start to return.
+const START_ABORT_GRACE: Duration = Duration::from_secs(5);
+const DELETE_RETRY_DELAY: Duration = Duration::from_millis(100);
+/// How long a forced delete waits for the container to exit after SIGKILL.
+const FORCE_DELETE_TIMEOUT: Duration = Duration::from_secs(5);
+const OPTIONS_RETRY_DELAY: Duration = Duration::from_millis(50);
+/// How long to wait for containerd to attach the writer of a stdin FIFO.
+const STDIN_WRITER_TIMEOUT: Duration = Duration::from_secs(10);
+/// Exit code recorded when the exit status of the init process can't be
+/// collected, e.g. because it exited while no shim was running.
+const UNKNOWN_EXIT_CODE: i32 = 255;
+/// Signals meant for the workload itself, e.g. to reload its config.
+const USER_SIGNALS: [i32; 3] = [libc::SIGHUP, libc::SIGUSR1, libc::SIGUSR2];
+/// Annotation adding a directory level below the namespace to the state
+/// root, so that containers of one namespace don't share a state parent.
+static STATE_SUBDIR_ANNOTATION: &str = "youki.shim.state-subdir";
+/// File in the container's state directory recording how it exited.
+static EXIT_STATUS_FILE: &str = "exit_status.json";
+/// libcontainer's state file in the container's state directory.
+static STATE_FILE: &str = "state.json";
+static CGROUP_ROOT: &str = "/sys/fs/cgroup";
+static SHIM_NAME_V1: &str = "io.containerd.youki.v1";
+static SHIM_NAME_V2: &str = "io.containerd.youki.v2";
+/// Overrides the runtime name the shim registers under.
+const SHIM_NAME_ENV: &str = "YOUKI_SHIM_NAME";
+
+/// How the init process of a container exited.
+#[derive(Clone, Copy, Debug)]
+pub struct ExitInfo {
+    /// The code reported to containerd, 128 + signal for a signaled process.
+    pub code: u32,
+    pub signaled: bool,
+    pub signal: Option<i32>,
+    /// Whether the kernel OOM killer killed a process of the container.
+    pub oom_killed: bool,
+    pub at: DateTime<Utc>,
+}
+
+impl ExitInfo {
+    fn exited(status: i32) -> Self {
+        ExitInfo {
+            code: status as u32,
+            signaled: false,
+            signal: None,
+            oom_killed: false,
+            at: Utc::now(),
+        }
+    }
+
+    fn signaled(signal: i32) -> Self {
+        ExitInfo {
+            code: 128 + signal as u32,
+            signaled: true,
+            signal: Some(signal),
+            oom_killed: false,
+            at: Utc::now(),
+        }
+    }
+}
+
+/// On-disk form of `ExitInfo`, so that a shim started after the container
+/// exited can still report how.
+#[derive(Serialize, Deserialize)]
+struct PersistedExit {
+    code: u32,
+    signaled: bool,
+    signal: Option<i32>,
+    oom_killed: bool,
+    /// RFC 3339
+    at: String,
+}
+
+impl ExitInfo {
+    fn persist(&self, path: &Path) -> Result<()> {
+        let persisted = PersistedExit {
+            code: self.code,
+            signaled: self.signaled,
+            signal: self.signal,
+            oom_killed: self.oom_killed,
+            at: self.at.to_rfc3339(),
+        };
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, serde_json::to_vec(&persisted)?)?;
+        fs::rename(&tmp, path)?;
+        Ok(())
+    }
+
+    /// Reads a persisted exit back, `None` if there is none or it is unreadable.
+    fn load(path: &Path) -> Option<Self> {
+        let data = fs::read(path).ok()?;
+        let persisted: PersistedExit = match serde_json::from_slice(&data) {
+            Ok(p) => p,
+            Err(err) => {
+                log::warn!("ignoring invalid {}: {}", path.display(), err);
+                return None;
+            }
+        };
+        let at = DateTime::parse_from_rfc3339(&persisted.at)
+            .map(|at| at.with_timezone(&Utc))
+            .unwrap_or_else(|_| Utc::now());
+        Some(ExitInfo {
+            code: persisted.code,
+            signaled: persisted.signaled,
+            signal: persisted.signal,
+            oom_killed: persisted.oom_killed,
+            at,
+        })
+    }
+}
 
 pub struct MyContainer {
     exit_code: ExitCode,
+    exit_info: Arc<Mutex<Option<ExitInfo>>>,
     id: String,
     stdin: String,
     stdout: String,
@@ -41,30 +183,204 @@ pub struct MyContainer {
     bundle: String,
 
     rootdir: PathBuf,
+    /// Whether `rootdir` is a `youki.shim.state-subdir` of its own, which is
+    /// removed along with the last container in it.
+    state_subdir: bool,
+    options: Options,
+    /// The container as last loaded, with the mtime of its state file.
+    container_cache: Mutex<Option<(SystemTime, Container)>>,
+    init_error: Option<Error>,
+    _activity: idle::Activity,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Default)]
 struct Options {
     root: Option<PathBuf>,
+    /// Overrides the namespace containerd passes to the shim. Useful when the
+    /// shim runs outside of Kubernetes and containerd's namespaces don't map
+    /// to the desired state isolation.
+    namespace: Option<String>,
+    /// Whether the cgroups of the container are managed through systemd.
+    /// Detected from the host and the spec when unset, see
+    /// `use_systemd_cgroup`.
+    systemd_cgroup: Option<bool>,
+    /// How long a forced delete waits for the container to exit after
+    /// SIGTERM before sending SIGKILL. `0` kills immediately.
+    stop_timeout_secs: Option<u64>,
+    /// Have `delete` stop a container that is still running instead of
+    /// failing, see `stop_timeout_secs`. Defaults to `false`.
+    force_delete: Option<bool>,
+    /// Run the container without a stdio stream the shim isn't permitted to
+    /// open instead of failing to start it. Defaults to `false`.
+    ignore_stdio_errors: Option<bool>,
+    /// Absolute path of a file to write the pid of the container's init
+    /// process to, for external supervision. Removed on delete. An existing
+    /// file that isn't a pidfile is never replaced.
+    pidfile: Option<PathBuf>,
+    /// Fail to start a container whose spec sets limits for cgroup
+    /// controllers the host doesn't provide, instead of dropping the limits
+    /// with a warning. Defaults to `false`.
+    strict_cgroups: Option<bool>,
+    /// How often building the container is retried on a transient failure
+    /// such as EBUSY from a cgroup. Defaults to 3, `0` disables retries.
+    start_retries: Option<u32>,
+    /// How long `start` waits for the workload to run, including compiling a
+    /// wasm module, before killing it and failing. `0` waits indefinitely.
+    start_timeout_secs: Option<u64>,
+    /// wasm proposals to enable in the wasmtime engine, e.g. `["threads"]`.
+    /// Unknown names are ignored with a warning.
+    engine_features: Option<Vec<String>>,
+    /// How much output of a container with `process.terminal` set is kept in
+    /// memory while nobody reads its stdout, e.g. when it runs detached until
+    /// a client attaches. Beyond that the oldest output is dropped. Defaults
+    /// to 1 MiB.
+    terminal_buffer_bytes: Option<usize>,
+    /// Env vars every wasm workload gets, e.g. the node's region. The spec's
+    /// env, its hostname and a `youki.wasm.env-file` win on conflicting keys.
+    default_env: Option<HashMap<String, String>>,
+    /// How much the wasm engine optimizes modules: `none`, `speed` or
+    /// `speed_and_size`. Defaults to `speed`. A `youki.wasm.opt_level`
+    /// annotation wins for its container.
+    opt_level: Option<String>,
 }
 
-fn determine_rootdir<P: AsRef<Path>>(bundle: P, namespace: String) -> Result<PathBuf, Error> {
-    let mut file = match File::open(bundle.as_ref().join("options.json")) {
-        Ok(f) => f,
-        Err(err) => match err.kind() {
-            ErrorKind::NotFound => {
-                return Ok(<&str as Into<PathBuf>>::into(DEFAULT_CONTAINER_ROOT_DIR).join(namespace))
+/// The `opt_level` option, `OptLevel::default()` when unset.
+fn default_opt_level(options: &Options) -> Result<OptLevel, Error> {
+    match &options.opt_level {
+        Some(level) => level
+            .parse()
+            .map_err(|err| Error::InvalidArgument(format!("{:#}", err))),
+        None => Ok(OptLevel::default()),
+    }
+}
+
+/// Rejects `default_env` entries WASI can't represent: keys have to be
+/// non-empty and free of `=`, and neither may contain NUL bytes.
+fn validate_default_env(options: &Options) -> Result<(), Error> {
+    for (key, value) in options.default_env.iter().flatten() {
+        if key.is_empty() || key.contains(['=', '\0']) || value.contains('\0') {
+            return Err(Error::InvalidArgument(format!(
+                "invalid default_env entry {:?}: keys must be non-empty without '=' or NUL, \
+                 values without NUL",
+                key
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Reads the bundle's options.json. A missing or empty file means default
+/// options. containerd may still be writing the file, so a parse failure is
+/// retried once after a short delay.
+fn load_options<P: AsRef<Path>>(bundle: P) -> Result<Options, Error> {
+    let path = bundle.as_ref().join("options.json");
+    let mut retried = false;
+    loop {
+        let data = match fs::read_to_string(&path) {
+            Ok(data) => data,
+            Err(err) => match err.kind() {
+                ErrorKind::NotFound => return Ok(Options::default()),
+                _ => return Err(err.into()),
+            },
+        };
+        if data.trim().is_empty() {
+            return Ok(Options::default());
+        }
+        match serde_json::from_str(&data) {
+            Ok(options) => return Ok(options),
+            Err(err) if !retried => {
+                log::debug!("could not parse {}: {}: {:?}", path.display(), err, data);
+                retried = true;
+                thread::sleep(OPTIONS_RETRY_DELAY);
+            }
+            Err(err) => {
+                log::debug!("could not parse {}: {}: {:?}", path.display(), err, data);
+                return Err(err.into());
             }
-            _ => return Err(err.into()),
+        }
+    }
+}
+
+/// Picks the namespace used to isolate container state. An override from
+/// options.json wins over the one containerd passed, and an empty namespace
+/// (containerd's "no namespace") falls back to containerd's default namespace.
+fn resolve_namespace(options: &Options, namespace: String) -> Result<String, Error> {
+    let namespace = match &options.namespace {
+        Some(ns) => ns.clone(),
+        None => namespace,
+    };
+    if namespace.is_empty() {
+        return Ok(DEFAULT_NAMESPACE.to_string());
+    }
+    validate_path_component("namespace", &namespace)?;
+    Ok(namespace)
+}
+
+/// Rejects values that would not stay a single directory below the root.
+fn validate_path_component(kind: &str, value: &str) -> Result<(), Error> {
+    if value.is_empty() || value == "." || value == ".." || value.contains(['/', '\\', '\0']) {
+        return Err(Error::InvalidArgument(format!(
+            "invalid {} {:?}: must not contain path separators",
+            kind, value
+        )));
+    }
+    Ok(())
+}
+
+/// The extra state directory level requested through the
+/// `youki.shim.state-subdir` annotation.
+fn state_subdir(spec: &Spec) -> Result<Option<&str>, Error> {
+    let subdir = match spec
+        .annotations()
+        .as_ref()
+        .and_then(|a| a.get(STATE_SUBDIR_ANNOTATION))
+    {
+        Some(subdir) => subdir,
+        None => return Ok(None),
+    };
+    validate_path_component(STATE_SUBDIR_ANNOTATION, subdir)?;
+    Ok(Some(subdir))
+}
+
+/// Where container state lives: the `root` from options.json, else the
+/// shim-wide `YOUKI_SHIM_ROOT`, else `DEFAULT_CONTAINER_ROOT_DIR`, joined
+/// with the namespace and the `youki.shim.state-subdir` of the spec, if any.
+fn determine_rootdir(
+    options: &Options,
+    namespace: String,
+    spec: Option<&Spec>,
+) -> Result<PathBuf, Error> {
+    let namespace = resolve_namespace(options, namespace)?;
+    log::info!(">>> Resolved namespace: {}", namespace);
+    let root = match &options.root {
+        Some(root) => validate_root(root)?,
+        None => match std::env::var_os(ROOT_ENV) {
+            Some(root) if !root.is_empty() => validate_root(Path::new(&root))?,
+            _ => PathBuf::from(DEFAULT_CONTAINER_ROOT_DIR),
         },
     };
-    let mut data = String::new();
-    file.read_to_string(&mut data)?;
-    let options: Options = serde_json::from_str(&data)?;
-    Ok(options
-        .root
-        .unwrap_or(PathBuf::from(DEFAULT_CONTAINER_ROOT_DIR))
-        .join(namespace))
+    let rootdir = root.join(namespace);
+    match spec.map(state_subdir).transpose()?.flatten() {
+        Some(subdir) => Ok(rootdir.join(subdir)),
+        None => Ok(rootdir),
+    }
+}
+
+/// options.json comes with the bundle, so don't let its `root` point state
+/// somewhere unexpected: it has to be absolute and free of `..`. An existing
+/// root is canonicalized so symlinks are resolved up front.
+fn validate_root(root: &Path) -> Result<PathBuf, Error> {
+    if !root.is_absolute() || root.components().any(|c| c == Component::ParentDir) {
+        return Err(Error::InvalidArgument(format!(
+            "invalid root {}: must be an absolute path without '..'",
+            root.display()
+        )));
+    }
+    match fs::canonicalize(root) {
+        Ok(root) => Ok(root),
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(root.to_path_buf()),
+        Err(err) => Err(err.into()),
+    }
 }
 
 impl Instance for MyContainer {
@@ -72,112 +388,155 @@ impl Instance for MyContainer {
 
     fn new(id: String, cfg: Option<&InstanceConfig<Self::E>>) -> Self {
         log::info!(">>> New instance: {}", id);
-        let cfg = cfg.unwrap();
-        let bundle = cfg.get_bundle().unwrap_or_default();
-        log::info!(">>> Bundle: {:?}", bundle);
-        let namespace = cfg.get_namespace();
-        log::info!(">>> Namespace: {:?}", namespace);
-        let rootdir = determine_rootdir(bundle.as_str(), namespace).unwrap();
-        log::info!(">>> Rootdir: {:?}", rootdir);
-        MyContainer {
+        let mut container = MyContainer {
             id,
             exit_code: Arc::new((Mutex::new(None), Condvar::new())),
-            stdin: cfg.get_stdin().unwrap_or_default(),
-            stdout: cfg.get_stdout().unwrap_or_default(),
-            stderr: cfg.get_stderr().unwrap_or_default(),
-            bundle: bundle.clone(),
-            rootdir,
+            exit_info: Arc::new(Mutex::new(None)),
+            stdin: String::new(),
+            stdout: String::new(),
+            stderr: String::new(),
+            bundle: String::new(),
+            rootdir: PathBuf::from(DEFAULT_CONTAINER_ROOT_DIR),
+            state_subdir: false,
+            options: Options::default(),
+            container_cache: Mutex::new(None),
+            init_error: None,
+            _activity: idle::Activity::new(),
+        };
+        // Instance::new can't fail, so keep the error around and report it
+        // from start() instead of panicking the shim.
+        if let Err(err) = container.init(cfg) {
+            error!("failed to initialize instance {}: {}", container.id, err);
+            container.init_error = Some(err);
+        } else if let Err(err) = container.reattach() {
+            error!("failed to reattach to container {}: {}", container.id, err);
         }
+        container
     }
 
     fn start(&self) -> Result<u32, containerd_shim_wasm::sandbox::Error> {
+        let _log = logging::container_context(&self.id);
         log::info!(">>> Starting container {}", self.id);
+        idle::touch();
+        let begin = Instant::now();
+        if let Some(err) = &self.init_error {
+            metrics::start_failed();
+            return Err(error::with_context(err, "failed to initialize container"));
+        }
 
         log::info!(">>> About to build DefaultContainer {}", self.id);
-        let mut container = match self.build_executor() {
+        let mut container = match self.build_with_retry() {
             Ok(c) => c,
             Err(err) => {
                 error!("failed to build container: {}", err);
-                return Err(Error::Others(err.to_string()));
+                metrics::start_failed();
+                return Err(ShimError::from(err).into());
             }
         };
         log::info!(">>> Built DefaultContainer {}", self.id);
         let code = self.exit_code.clone();
+        let exit_info = self.exit_info.clone();
         log::info!(">>> About to run container {}", self.id);
-        let pid = container.pid().unwrap();
-        match container.start() {
-            Ok(_) => {}
+        // The init process is forked by build(), so it has a pid before it is
+        // started. The priority and the pidfd need it before the workload runs.
+        let pid = match container.pid() {
+            Some(pid) => pid,
+            None => {
+                error!("container {} has no pid after build", self.id);
+                self.abort_start(None, None);
+                metrics::start_failed();
+                return Err(ShimError::Engine("container has no pid after build".into()).into());
+            }
+        };
+        if let Err(err) = self.apply_priority(pid) {
+            error!("failed to set container priority: {}", err);
+            self.abort_start(Some(pid), None);
+            metrics::start_failed();
+            return Err(ShimError::from(err).into());
+        }
+        if let Some(pidfile) = &self.options.pidfile {
+            if let Err(err) = write_pidfile(pidfile, pid) {
+                error!("failed to write pidfile: {:#}", err);
+                self.abort_start(Some(pid), None);
+                metrics::start_failed();
+                return Err(ShimError::from(err).into());
+            }
+        }
+        // Hold a pidfd for the init process so the reaper waits on this exact
+        // process even if the pid gets recycled after a delete.
+        let pidfd = match pidfd_open(pid) {
+            Ok(fd) => Some(fd),
             Err(err) => {
-                error!("failed to start container: {}", err);
-                return Err(Error::Others(err.to_string()));
+                log::warn!("pidfd_open failed, falling back to waiting on pid: {}", err);
+                None
             }
+        };
+        let oom_watcher = OomWatcher::new(pid);
+        let status_file = self.exit_status_file();
+        if let Err(err) = self.start_with_timeout(container, pid) {
+            error!("failed to start container: {}", err);
+            self.abort_start(Some(pid), pidfd.as_ref().map(|fd| fd.as_raw_fd()));
+            metrics::start_failed();
+            return Err(err);
         }
         log::info!(">>> Running container pid: {}", pid);
         thread::spawn(move || {
-            let (lock, cvar) = &*code;
-            let status = match waitid(WaitID::Pid(pid), WaitPidFlag::WEXITED) {
-                Ok(WaitStatus::Exited(_, status)) => status,
-                Ok(WaitStatus::Signaled(_, sig, _)) => sig as i32,
-                Ok(_) => 0,
-                Err(e) => {
-                    if e == Errno::ECHILD {
-                        0
-                    } else {
-                        panic!("waitpid failed: {}", e);
-                    }
-                }
-            } as u32;
-            let mut ec = lock.lock().unwrap();
-            *ec = Some((status, Utc::now()));
-            drop(ec);
-            cvar.notify_all();
+            let mut info = wait_exit(pid, pidfd.as_ref().map(|fd| fd.as_raw_fd()));
+            info.oom_killed = oom_watcher.is_some_and(|w| w.oom_killed());
+            if info.oom_killed {
+                log::info!(">>> Container process {} was OOM killed", pid);
+            }
+            record_exit(&code, &exit_info, info, &status_file);
+            metrics::exited();
         });
+        metrics::started(begin.elapsed());
         Ok(pid.as_raw() as u32)
     }
 
     fn kill(&self, signal: u32) -> Result<(), containerd_shim_wasm::sandbox::Error> {
+        let _log = logging::container_context(&self.id);
         log::info!(">>> Killing container {}", self.id);
-        if signal as i32 != SIGKILL && signal as i32 != SIGINT {
-            return Err(Error::InvalidArgument(
-                "only SIGKILL and SIGINT are supported".to_string(),
-            ));
+        metrics::killed();
+        let raw_signal = signal as i32;
+        let signal = match Signal::try_from(raw_signal) {
+            Ok(sig) => sig,
+            Err(_) => return Err(Error::InvalidArgument(format!("invalid signal {}", signal))),
+        };
+
+        if USER_SIGNALS.contains(&raw_signal) && self.runs_wasm() {
+            // The guest can't install handlers, so the engine's process
+            // would just die from the default action.
+            log::info!(
+                ">>> Not delivering signal {} to wasm container {}: guests can't handle it",
+                raw_signal,
+                self.id
+            );
+            return Ok(());
         }
 
+        self.invalidate_container_cache();
         let mut container = load_container(&self.rootdir, self.id.as_str())?;
-        match container.kill(Signal::try_from(signal as i32).unwrap(), true) {
+        // A frozen process can't act on the signal, thaw it first.
+        if container.status() == ContainerStatus::Paused {
+            if let Err(e) = container.resume() {
+                log::error!("failed to resume paused container before kill: {}", e);
+                return Err(ShimError::Cgroup(e.to_string()).into());
+            }
+        }
+        match container.kill(signal, true) {
             Ok(_) => Ok(()),
             Err(e) => {
                 if container.status() == ContainerStatus::Stopped {
-                    return Err(Error::Others("container not running".into()));
+                    return Err(ShimError::Engine("container not running".into()).into());
                 }
                 log::error!("failed to kill container: {}", e);
-                Err(Error::Others(e.to_string()))
+                Err(ShimError::Engine(e.to_string()).into())
             }
         }
     }
 
     fn delete(&self) -> Result<(), containerd_shim_wasm::sandbox::Error> {
-        log::info!(">>> Deleting container {}", self.id);
-        match container_exists(&self.rootdir, self.id.as_str()) {
-            Ok(exists) => {
-                if !exists {
-                    return Ok(());
-                }
-            }
-            Err(err) => {
-                error!("could not find the container, skipping cleanup: {}", err);
-                return Ok(());
-            }
-        }
-        match load_container(&self.rootdir, self.id.as_str()) {
-            Ok(mut container) => container.delete(true).unwrap(),
-            Err(err) => {
-                error!("could not find the container, skipping cleanup: {}", err);
-                return Ok(());
-            }
-        }
-
-        Ok(())
+        self.delete_container(self.options.force_delete.unwrap_or(false))
     }
 
     fn wait(&self, waiter: &Wait) -> Result<(), containerd_shim_wasm::sandbox::Error> {
@@ -189,82 +548,2051 @@ impl Instance for MyContainer {
 
 /// containerd can send an empty path or a non-existant path
 /// In both these cases we should just assume that the stdio stream was not setup (intentionally)
-/// Any other error is a real error.
-fn maybe_open_stdio(path: &str) -> Result<Option<RawFd>, Error> {
+/// Any other error is a real error, except that a path we aren't permitted to
+/// open (e.g. because of SELinux or AppArmor) is skipped when `ignore_denied`.
+/// Input is opened read-only: holding a write end of the stdin FIFO would
+/// keep the workload from ever seeing EOF once containerd closes it.
+fn maybe_open_stdio(
+    path: &str,
+    input: bool,
+    ignore_denied: bool,
+) -> Result<Option<OwnedFd>, Error> {
     if path.is_empty() {
         return Ok(None);
     }
-    match OpenOptions::new().read(true).write(true).open(path) {
-        Ok(f) => Ok(Some(f.into_raw_fd())),
+    let fifo = fs::metadata(path)
+        .map(|m| m.file_type().is_fifo())
+        .unwrap_or(false);
+    // Opening a FIFO can block until the other end is attached. Outputs are
+    // opened non-blocking and switched back to blocking mode once we hold
+    // the fd. The stdin FIFO has to wait for its writer, see
+    // `open_fifo_reader`.
+    let opened = if fifo && input {
+        open_fifo_reader(path, STDIN_WRITER_TIMEOUT)
+    } else {
+        let mut opts = OpenOptions::new();
+        opts.read(true).write(!input);
+        if fifo {
+            opts.custom_flags(libc::O_NONBLOCK);
+        }
+        opts.open(path)
+    };
+    match opened {
+        Ok(f) => {
+            if fifo {
+                clear_nonblocking(&f).map_err(std::io::Error::from)?;
+            }
+            Ok(Some(f.into()))
+        }
         Err(err) => match err.kind() {
             ErrorKind::NotFound => Ok(None),
+            ErrorKind::PermissionDenied => {
+                error!(
+                    "permission denied opening stdio {} (errno {}): {}",
+                    path,
+                    err.raw_os_error().unwrap_or_default(),
+                    err
+                );
+                if ignore_denied {
+                    Ok(None)
+                } else {
+                    Err(err.into())
+                }
+            }
             _ => Err(err.into()),
         },
     }
 }
 
+/// Opens the FIFO at `path` for reading once a writer is attached. A reader
+/// that gets there first sees EOF as soon as it reads, so the blocking open
+/// runs on a helper thread. Without a writer within `timeout` the open is
+/// woken up by a writer of our own that is closed right away, and the
+/// workload sees an empty stdin.
+fn open_fifo_reader(path: &str, timeout: Duration) -> std::io::Result<File> {
+    let (tx, rx) = mpsc::channel();
+    let reader_path = path.to_owned();
+    thread::spawn(move || {
+        let _ = tx.send(File::open(reader_path));
+    });
+    match rx.recv_timeout(timeout) {
+        Ok(res) => res,
+        Err(_) => {
+            log::warn!("no writer attached to {} within {:?}", path, timeout);
+            // the pending reader lets this succeed without blocking
+            let writer = OpenOptions::new()
+                .write(true)
+                .custom_flags(libc::O_NONBLOCK)
+                .open(path)?;
+            let res = rx.recv().map_err(|_| {
+                std::io::Error::new(ErrorKind::Other, format!("could not open {}", path))
+            })?;
+            drop(writer);
+            res
+        }
+    }
+}
+
+fn clear_nonblocking(f: &File) -> nix::Result<()> {
+    let flags = OFlag::from_bits_truncate(fcntl(f.as_raw_fd(), FcntlArg::F_GETFL)?);
+    fcntl(f.as_raw_fd(), FcntlArg::F_SETFL(flags & !OFlag::O_NONBLOCK))?;
+    Ok(())
+}
+
+/// Points one of the shim's std streams at a container stdio fd while alive.
+/// On drop the original stream is restored and its backup closed.
+struct StdioGuard {
+    target: RawFd,
+    backup: RawFd,
+}
+
+impl StdioGuard {
+    fn redirect(fd: &OwnedFd, target: RawFd) -> nix::Result<Self> {
+        let backup = dup(target)?;
+        if let Err(err) = dup2(fd.as_raw_fd(), target) {
+            let _ = close(backup);
+            return Err(err);
+        }
+        Ok(StdioGuard { target, backup })
+    }
+}
+
+impl Drop for StdioGuard {
+    fn drop(&mut self) {
+        if let Err(err) = dup2(self.backup, self.target) {
+            error!("failed to restore fd {}: {}", self.target, err);
+        }
+        let _ = close(self.backup);
+    }
+}
+
+/// Whether the failure is one that retrying may get past.
+fn is_transient(err: &anyhow::Error) -> bool {
+    err.chain().any(|cause| {
+        let errno = match cause.downcast_ref::<Errno>() {
+            Some(errno) => Some(*errno),
+            None => cause
+                .downcast_ref::<std::io::Error>()
+                .and_then(|e| e.raw_os_error())
+                .map(Errno::from_i32),
+        };
+        matches!(errno, Some(Errno::EBUSY | Errno::EAGAIN))
+    })
+}
+
+/// Detects OOM kills in a container through the `oom_kill` counter of its
+/// cgroup v2 `memory.events`. The cgroup outlives the process until the
+/// container is deleted, so the counter can be checked once the process
+/// exited.
+struct OomWatcher {
+    events: PathBuf,
+    initial: u64,
+}
+
+impl OomWatcher {
+    /// Returns `None` when the cgroup of `pid` exposes no memory events,
+    /// e.g. on cgroup v1 hosts.
+    fn new(pid: Pid) -> Option<Self> {
+        let cgroup = fs::read_to_string(format!("/proc/{}/cgroup", pid)).ok()?;
+        let path = cgroup.lines().find_map(|l| l.strip_prefix("0::"))?;
+        let events = Path::new(CGROUP_ROOT)
+            .join(path.trim_start_matches('/'))
+            .join("memory.events");
+        let initial = read_oom_kills(&events)?;
+        Some(OomWatcher { events, initial })
+    }
+
+    fn oom_killed(&self) -> bool {
+        read_oom_kills(&self.events)
+            .map(|kills| kills > self.initial)
+            .unwrap_or(false)
+    }
+}
+
+fn read_oom_kills(events: &Path) -> Option<u64> {
+    fs::read_to_string(events)
+        .ok()?
+        .lines()
+        .find_map(|l| l.strip_prefix("oom_kill "))?
+        .trim()
+        .parse()
+        .ok()
+}
+
+/// Waits for the process to exit, through its pidfd when there is one.
+/// Interrupted waits are retried, and a wait that fails otherwise is reported
+/// as `UNKNOWN_EXIT_CODE` so that waiters are always released.
+fn wait_exit(pid: Pid, pidfd: Option<RawFd>) -> ExitInfo {
+    let status = loop {
+        let id = match pidfd {
+            Some(fd) => WaitID::PIDFd(fd),
+            None => WaitID::Pid(pid),
+        };
+        match waitid(id, WaitPidFlag::WEXITED) {
+            Err(Errno::EINTR) => continue,
+            res => break res,
+        }
+    };
+    match status {
+        Ok(WaitStatus::Exited(_, status)) => ExitInfo::exited(status),
+        Ok(WaitStatus::Signaled(_, sig, _)) => ExitInfo::signaled(sig as i32),
+        Ok(_) => ExitInfo::exited(0),
+        Err(Errno::ECHILD) => ExitInfo::exited(0),
+        Err(err) => {
+            error!("waitid for pid {} failed: {}", pid, err);
+            ExitInfo::exited(UNKNOWN_EXIT_CODE)
+        }
+    }
+}
+
+/// The name and the state letter of process `pid` from `/proc/<pid>/stat`,
+/// `None` once the process is gone.
+fn process_state(pid: Pid) -> Option<(String, char)> {
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    // the name is in parentheses and may itself contain them
+    let start = stat.find('(')?;
+    let end = stat.rfind(')')?;
+    let name = stat.get(start + 1..end)?.to_string();
+    let state = stat.get(end + 1..)?.trim_start().chars().next()?;
+    Some((name, state))
+}
+
+/// Polls until process `pid` no longer has the name `init_name` or exited.
+/// Returns `false` if neither happened by `deadline`.
+fn wait_until_running(pid: Pid, init_name: &str, deadline: Instant) -> bool {
+    loop {
+        match process_state(pid) {
+            Some((name, state)) if name == init_name && state != 'Z' => {}
+            _ => return true,
+        }
+        if Instant::now() >= deadline {
+            return false;
+        }
+        thread::sleep(START_POLL_INTERVAL);
+    }
+}
+
+/// Publishes the exit of the init process to `wait` and `exit_info`, and
+/// persists it to `status_file` so that it survives a shim restart.
+fn record_exit(
+    code: &ExitCode,
+    exit_info: &Mutex<Option<ExitInfo>>,
+    info: ExitInfo,
+    status_file: &Path,
+) {
+    if let Err(err) = info.persist(status_file) {
+        log::warn!(
+            "failed to persist exit status to {}: {}",
+            status_file.display(),
+            err
+        );
+    }
+    *exit_info.lock().unwrap() = Some(info);
+    let (lock, cvar) = &**code;
+    let mut ec = lock.lock().unwrap();
+    *ec = Some((info.code, info.at));
+    drop(ec);
+    cvar.notify_all();
+}
+
+/// options.json comes with the bundle and the shim runs as root, so the
+/// pidfile has to be an absolute path free of `..`, like `validate_root`.
+fn validate_pidfile(path: &Path) -> Result<(), Error> {
+    if !path.is_absolute()
+        || path.components().any(|c| c == Component::ParentDir)
+        || path.file_name().is_none()
+    {
+        return Err(Error::InvalidArgument(format!(
+            "invalid pidfile {}: must be an absolute path without '..'",
+            path.display()
+        )));
+    }
+    Ok(())
+}
+
+/// Whether `path` is a regular file holding nothing but a pid, `pid` if
+/// given. Symlinks and anything else are never treated as a pidfile.
+fn is_pidfile(path: &Path, pid: Option<Pid>) -> bool {
+    let is_file = fs::symlink_metadata(path).is_ok_and(|m| m.file_type().is_file());
+    if !is_file {
+        return false;
+    }
+    match fs::read_to_string(path).map(|c| c.trim().parse::<i32>()) {
+        Ok(Ok(found)) => pid.is_none_or(|pid| pid.as_raw() == found),
+        _ => false,
+    }
+}
+
+/// Writes `pid` to `path` atomically, so that readers never see a partial
+/// pid. The directory has to exist. An existing file is only replaced if it
+/// is a pidfile itself.
+fn write_pidfile(path: &Path, pid: Pid) -> Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(_) if !is_pidfile(path, None) => {
+            bail!("refusing to replace {}: not a pidfile", path.display())
+        }
+        Err(err) if err.kind() != ErrorKind::NotFound => {
+            return Err(err).with_context(|| format!("could not write pidfile {}", path.display()))
+        }
+        _ => {}
+    }
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let tmp = path.with_file_name(format!(".{}.{}.tmp", name, std::process::id()));
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&tmp)
+        .with_context(|| format!("could not write pidfile {}", path.display()))?;
+    // the temporary file is ours from here on, don't leave it behind
+    if let Err(err) = file
+        .write_all(pid.to_string().as_bytes())
+        .and_then(|_| fs::rename(&tmp, path))
+    {
+        let _ = fs::remove_file(&tmp);
+        return Err(err).with_context(|| format!("could not write pidfile {}", path.display()));
+    }
+    Ok(())
+}
+
+/// Opens a pidfd referring to `pid`. Waiting on the pidfd instead of the raw
+/// pid makes the wait immune to pid reuse.
+fn pidfd_open(pid: Pid) -> nix::Result<OwnedFd> {
+    let res = unsafe { libc::syscall(libc::SYS_pidfd_open, pid.as_raw(), 0) };
+    Errno::result(res).map(|fd| unsafe { OwnedFd::from_raw_fd(fd as RawFd) })
+}
+
 impl MyContainer {
+    fn init(&mut self, cfg: Option<&InstanceConfig<()>>) -> Result<(), Error> {
+        let cfg = cfg.ok_or_else(|| Error::InvalidArgument("missing instance config".into()))?;
+        self.bundle = cfg.get_bundle().unwrap_or_default();
+        log::info!(">>> Bundle: {:?}", self.bundle);
+        let namespace = cfg.get_namespace();
+        log::info!(">>> Namespace: {:?}", namespace);
+        self.options = load_options(self.bundle.as_str())?;
+        validate_default_env(&self.options)?;
+        default_opt_level(&self.options)?;
+        if let Some(pidfile) = &self.options.pidfile {
+            validate_pidfile(pidfile)?;
+        }
+        // A bundle without a readable spec fails to start anyway, its state
+        // just doesn't get the annotated subdirectory.
+        let spec = load_spec(&self.bundle).ok();
+        self.state_subdir = spec
+            .as_ref()
+            .map(state_subdir)
+            .transpose()?
+            .flatten()
+            .is_some();
+        self.rootdir = determine_rootdir(&self.options, namespace, spec.as_ref())?;
+        log::info!(">>> Rootdir: {:?}", self.rootdir);
+        self.stdin = cfg.get_stdin().unwrap_or_default();
+        self.stdout = cfg.get_stdout().unwrap_or_default();
+        self.stderr = cfg.get_stderr().unwrap_or_default();
+        Ok(())
+    }
+
+    /// Picks up a container started by a previous shim process, so that
+    /// `wait` and exit reporting keep working across a shim restart.
+    fn reattach(&mut self) -> Result<()> {
+        if !self.rootdir.exists() || !container_exists(&self.rootdir, self.id.as_str())? {
+            return Ok(());
+        }
+        let container = self.cached_container()?;
+        log::info!(
+            ">>> Reattaching to container {} in {:?} state",
+            self.id,
+            container.status()
+        );
+        // An exit recorded by the previous shim is more accurate than what
+        // can be found out now.
+        let status_file = self.exit_status_file();
+        let last_exit = |status_file: &Path| {
+            ExitInfo::load(status_file).unwrap_or_else(|| ExitInfo::exited(UNKNOWN_EXIT_CODE))
+        };
+        match (container.status(), container.pid()) {
+            (ContainerStatus::Running | ContainerStatus::Paused, Some(pid)) => {
+                let pidfd = match pidfd_open(pid) {
+                    Ok(fd) => fd,
+                    // the process exited in the meantime
+                    Err(Errno::ESRCH) => {
+                        record_exit(
+                            &self.exit_code,
+                            &self.exit_info,
+                            last_exit(&status_file),
+                            &status_file,
+                        );
+                        return Ok(());
+                    }
+                    Err(err) => return Err(err.into()),
+                };
+                let code = self.exit_code.clone();
+                let exit_info = self.exit_info.clone();
+                thread::spawn(move || {
+                    // The process isn't our child anymore, so its status can't
+                    // be reaped. The pidfd still tells when it exits.
+                    let mut fds = [PollFd::new(pidfd.as_raw_fd(), PollFlags::POLLIN)];
+                    while let Err(Errno::EINTR) = poll(&mut fds, -1) {}
+                    record_exit(&code, &exit_info, last_exit(&status_file), &status_file);
+                });
+            }
+            (ContainerStatus::Stopped, _) => record_exit(
+                &self.exit_code,
+                &self.exit_info,
+                last_exit(&status_file),
+                &status_file,
+            ),
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn exit_status_file(&self) -> PathBuf {
+        self.rootdir.join(&self.id).join(EXIT_STATUS_FILE)
+    }
+
+    /// Best-effort removal of the container's state directory, so that the
+    /// id can be reused even when a regular delete failed.
+    fn remove_state_dir(&self) {
+        for dir in [self.rootdir.join(&self.id), self.adjusted_bundle_dir()] {
+            if let Err(err) = fs::remove_dir_all(&dir) {
+                if err.kind() != ErrorKind::NotFound {
+                    error!("failed to remove {}: {}", dir.display(), err);
+                }
+            }
+        }
+        if self.state_subdir {
+            match fs::remove_dir(&self.rootdir) {
+                Ok(()) => {}
+                // still holds other containers
+                Err(err)
+                    if matches!(
+                        err.kind(),
+                        ErrorKind::NotFound | ErrorKind::DirectoryNotEmpty
+                    ) => {}
+                Err(err) => error!("failed to remove {}: {}", self.rootdir.display(), err),
+            }
+        }
+    }
+
+    /// Removes the pidfile written for the init process `pid`. A file that
+    /// doesn't hold that pid was replaced by someone else and is left alone.
+    fn remove_pidfile(&self, pid: Option<Pid>) {
+        if let Some(pidfile) = &self.options.pidfile {
+            if !is_pidfile(pidfile, pid) {
+                if pidfile.exists() {
+                    log::warn!("not removing {}: not our pidfile", pidfile.display());
+                }
+                return;
+            }
+            if let Err(err) = fs::remove_file(pidfile) {
+                if err.kind() != ErrorKind::NotFound {
+                    error!("failed to remove pidfile {}: {}", pidfile.display(), err);
+                }
+            }
+        }
+    }
+
+    /// Deletes the container. A running or paused container is only deleted
+    /// with `force`, after `stop` has stopped it.
+    fn delete_container(&self, force: bool) -> Result<(), Error> {
+        let _log = logging::container_context(&self.id);
+        log::info!(">>> Deleting container {}", self.id);
+        metrics::deleted();
+        match container_exists(&self.rootdir, self.id.as_str()) {
+            Ok(exists) => {
+                if !exists {
+                    return Ok(());
+                }
+            }
+            Err(err) => {
+                error!("could not find the container, skipping cleanup: {}", err);
+                return Ok(());
+            }
+        }
+        if let Ok(container) = self.cached_container() {
+            if matches!(
+                container.status(),
+                ContainerStatus::Running | ContainerStatus::Paused
+            ) {
+                if !force {
+                    return Err(Error::FailedPrecondition(format!(
+                        "container {} is still running, stop it before deleting it",
+                        self.id
+                    )));
+                }
+                log::info!(">>> Stopping running container {} to delete it", self.id);
+                self.stop(libc::SIGTERM as u32)?;
+                if self.wait_timeout(FORCE_DELETE_TIMEOUT)?.is_none() {
+                    log::warn!(
+                        "container {} did not exit within {:?} of SIGKILL, deleting anyway",
+                        self.id,
+                        FORCE_DELETE_TIMEOUT
+                    );
+                }
+            }
+        }
+        let mut container = match load_container(&self.rootdir, self.id.as_str()) {
+            Ok(container) => container,
+            Err(err) => {
+                error!("could not load the container, removing its state: {}", err);
+                self.remove_state_dir();
+                return Ok(());
+            }
+        };
+        let pid = container.pid();
+        if let Err(err) = container.delete(true) {
+            // Removing the cgroup can fail with EBUSY while the kernel is
+            // still tearing down the container's processes.
+            log::warn!("failed to delete container, retrying: {}", err);
+            thread::sleep(DELETE_RETRY_DELAY);
+            if let Err(err) = container.delete(true) {
+                error!("failed to delete container: {}", err);
+                self.remove_state_dir();
+                return Err(ShimError::Engine(err.to_string()).into());
+            }
+        }
+        self.remove_pidfile(pid);
+        // libcontainer only removes the files it knows about, drop whatever
+        // the shim added to the state directory along with it.
+        self.remove_state_dir();
+
+        Ok(())
+    }
+
     fn build_executor(&self) -> Result<Container> {
         let syscall = create_syscall();
+        let spec = load_spec(&self.bundle)?;
+        let systemd = use_systemd_cgroup(&self.options, &spec, Path::new(CGROUP_ROOT));
+        validate_sysctls(&spec)?;
+        // applied once the init process exists, refuse bad values up front
+        requested_nice(&spec)?;
+        requested_oom_score_adj(&spec)?;
+        let adjusted_bundle =
+            self.check_cgroup_controllers(&spec, systemd, Path::new(CGROUP_ROOT))?;
+        let bundle = adjusted_bundle
+            .as_deref()
+            .unwrap_or(Path::new(&self.bundle));
+        let executors = executors(&self.options);
+        if logging::debug() {
+            log_spec(&spec, &executors);
+        }
+        check_handler(&spec, &executors)?;
         fs::create_dir_all(&self.rootdir)?;
         // verify that roodir is created
         assert!(self.rootdir.exists());
-        let stdin = maybe_open_stdio(self.stdin.as_str()).context("could not open stdin")?;
-        let stdout = maybe_open_stdio(self.stdout.as_str()).context("could not open stdout")?;
-        let stderr = maybe_open_stdio(self.stderr.as_str()).context("could not open stderr")?;
+        let ignore_denied = self.options.ignore_stdio_errors.unwrap_or(false);
+        let stdin = maybe_open_stdio(self.stdin.as_str(), true, ignore_denied)
+            .context("could not open stdin")?;
+        let stdout = maybe_open_stdio(self.stdout.as_str(), false, ignore_denied)
+            .context("could not open stdout")?;
+        let stderr = maybe_open_stdio(self.stderr.as_str(), false, ignore_denied)
+            .context("could not open stderr")?;
 
-        if let Some(stdin) = stdin {
-            let _ = dup(STDIN_FILENO)?;
-            dup2(stdin, STDIN_FILENO)?;
+        let terminal = spec
+            .process()
+            .as_ref()
+            .and_then(|p| p.terminal())
+            .unwrap_or(false);
+        if terminal {
+            return self.build_with_terminal(
+                syscall.as_ref(),
+                executors,
+                bundle,
+                systemd,
+                stdin,
+                stdout,
+            );
         }
 
-        if let Some(stdout) = stdout {
-            let _ = dup(STDOUT_FILENO)?;
-            dup2(stdout, STDOUT_FILENO)?;
-        }
-
-        if let Some(stderr) = stderr {
-            let _ = dup(STDERR_FILENO)?;
-            dup2(stderr, STDERR_FILENO)?;
-        }
+        // The container process inherits the shim's std streams when it is
+        // forked during build(). The guards put the shim's own streams back
+        // once the container has been built.
+        let _stdin = stdin
+            .map(|fd| StdioGuard::redirect(&fd, STDIN_FILENO))
+            .transpose()?;
+        let _stdout = stdout
+            .map(|fd| StdioGuard::redirect(&fd, STDOUT_FILENO))
+            .transpose()?;
+        let _stderr = stderr
+            .map(|fd| StdioGuard::redirect(&fd, STDERR_FILENO))
+            .transpose()?;
 
         let container = ContainerBuilder::new(self.id.clone(), syscall.as_ref())
-            .with_executor(vec![Box::<DefaultExecutor>::default()])?
+            .with_executor(executors)?
+            .with_root_path(self.rootdir.clone())?
+            .as_init(bundle)
+            .with_systemd(systemd)
+            .build()?;
+        Ok(container)
+    }
+
+    /// Builds a container whose stdio is a PTY. The PTY master is handed to
+    /// the shim over a console socket and wired to the stdin and stdout FIFOs.
+    fn build_with_terminal(
+        &self,
+        syscall: &dyn Syscall,
+        executors: Vec<Box<dyn Executor>>,
+        bundle: &Path,
+        systemd: bool,
+        stdin: Option<OwnedFd>,
+        stdout: Option<OwnedFd>,
+    ) -> Result<Container> {
+        let console = console::ConsoleSocket::new(self.console_socket_path())?;
+        let container = ContainerBuilder::new(self.id.clone(), syscall)
+            .with_executor(executors)?
             .with_root_path(self.rootdir.clone())?
-            .as_init(&self.bundle)
-            .with_systemd(false)
+            .with_console_socket(Some(console.path()))
+            .as_init(bundle)
+            .with_systemd(systemd)
             .build()?;
+        let master = console
+            .receive_master()
+            .context("could not receive the container's terminal")?;
+        let buffer_bytes = self
+            .options
+            .terminal_buffer_bytes
+            .unwrap_or(console::DEFAULT_BUFFER_BYTES);
+        console::forward(master, stdin, stdout, buffer_bytes)?;
         Ok(container)
     }
+
+    /// Builds the container, retrying with exponential backoff on failures
+    /// that are expected to go away, e.g. a cgroup still busy being torn down
+    /// after a previous container with the same path.
+    fn build_with_retry(&self) -> Result<Container> {
+        let retries = self.options.start_retries.unwrap_or(DEFAULT_START_RETRIES);
+        let mut delay = START_RETRY_DELAY;
+        let mut attempt = 0;
+        loop {
+            match self.build_executor() {
+                Ok(container) => return Ok(container),
+                Err(err) if attempt < retries && is_transient(&err) => {
+                    attempt += 1;
+                    log::warn!(
+                        "building container failed, retry {}/{} in {:?}: {:#}",
+                        attempt,
+                        retries,
+                        delay,
+                        err
+                    );
+                    // the failed attempt may have left its state behind
+                    self.remove_state_dir();
+                    thread::sleep(delay);
+                    delay *= 2;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    /// Makes sure the cgroup v2 controllers the spec's limits need are
+    /// available to the container's cgroup under the cgroup mount
+    /// `cgroup_root`, which they may not be e.g. when running in a container,
+    /// see `available_controllers`. With `strict_cgroups` a missing controller
+    /// fails the start. Otherwise its limits are dropped with a warning and the
+    /// container is built from an adjusted copy of the bundle, whose path is
+    /// returned. containerd's bundle itself is never changed.
+    fn check_cgroup_controllers(
+        &self,
+        spec: &Spec,
+        systemd: bool,
+        cgroup_root: &Path,
+    ) -> Result<Option<PathBuf>> {
+        if detect_cgroup_mode(cgroup_root) != CgroupMode::Unified {
+            return Ok(None);
+        }
+        let resources = match spec.linux().as_ref().and_then(|l| l.resources().as_ref()) {
+            Some(resources) => resources,
+            None => return Ok(None),
+        };
+        let (parent, available) = match available_controllers(cgroup_root, spec, systemd) {
+            Ok(available) => available,
+            Err(err) => {
+                log::warn!("could not read available cgroup controllers: {:#}", err);
+                return Ok(None);
+            }
+        };
+        let missing: Vec<&str> = required_controllers(resources)
+            .into_iter()
+            .filter(|c| !available.iter().any(|a| a == c))
+            .collect();
+        if missing.is_empty() {
+            return Ok(None);
+        }
+        if self.options.strict_cgroups.unwrap_or(false) {
+            bail!(
+                "cgroup controller {:?} required by the spec's resources is not available in {}",
+                missing[0],
+                parent.display()
+            );
+        }
+
+        let mut resources = resources.clone();
+        for controller in &missing {
+            log::warn!(
+                "cgroup controller {:?} is not available in {}, ignoring its limits",
+                controller,
+                parent.display()
+            );
+            drop_controller_limits(&mut resources, controller);
+        }
+        let mut linux = spec.linux().clone().unwrap_or_default();
+        linux.set_resources(Some(resources));
+        let mut spec = spec.clone();
+        spec.set_linux(Some(linux));
+        // the copy lives elsewhere, so a relative rootfs has to be resolved
+        if let Some(mut root) = spec.root().clone() {
+            if root.path().is_relative() {
+                root.set_path(Path::new(&self.bundle).join(root.path()));
+                spec.set_root(Some(root));
+            }
+        }
+        let bundle = self.adjusted_bundle_dir();
+        fs::create_dir_all(&bundle)
+            .with_context(|| format!("could not create {}", bundle.display()))?;
+        spec.save(bundle.join("config.json"))
+            .context("could not write the adjusted spec")?;
+        Ok(Some(bundle))
+    }
+
+    /// Where the console socket of the container is created. Like the
+    /// adjusted bundle it can't live in the state directory, and socket paths
+    /// are limited to 108 bytes, so the name is kept short.
+    fn console_socket_path(&self) -> PathBuf {
+        self.rootdir.join(format!(".{}.pty", self.id))
+    }
+
+    /// Where the bundle adjusted by `check_cgroup_controllers` is kept. It
+    /// can't live in the state directory, which libcontainer creates itself.
+    fn adjusted_bundle_dir(&self) -> PathBuf {
+        self.rootdir.join(format!(".{}.bundle", self.id))
+    }
+
+    /// Starts the container and waits for its workload to run, giving up
+    /// after the configured start timeout. libcontainer's start returns as
+    /// soon as it told the init process to go ahead, a wasm module is only
+    /// compiled after that. The workload runs once the init process changed
+    /// its name, by exec'ing or through `youki_wasm_common::mark_running`, or
+    /// exited. A container that didn't start in time is killed, the caller
+    /// cleans it up with `abort_start`.
+    fn start_with_timeout(&self, mut container: Container, pid: Pid) -> Result<(), Error> {
+        let timeout = Duration::from_secs(
+            self.options
+                .start_timeout_secs
+                .unwrap_or(DEFAULT_START_TIMEOUT_SECS),
+        );
+        if timeout.is_zero() {
+            return container
+                .start()
+                .map(|_| ())
+                .map_err(|err| ShimError::from(anyhow::Error::from(err)).into());
+        }
+        let deadline = Instant::now() + timeout;
+        let init_name = process_state(pid).map(|(name, _)| name);
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            let _ = tx.send(container.start().map(|_| ()));
+        });
+        let running = match rx.recv_timeout(timeout) {
+            Ok(res) => {
+                res.map_err(|err| ShimError::from(anyhow::Error::from(err)))?;
+                match init_name {
+                    Some(name) => wait_until_running(pid, &name, deadline),
+                    None => true,
+                }
+            }
+            Err(_) => false,
+        };
+        if running {
+            return Ok(());
+        }
+        if let Err(err) = nix::sys::signal::kill(pid, nix::sys::signal::SIGKILL) {
+            error!("failed to kill container that did not start: {}", err);
+        }
+        // The start thread owns the container and may still save its state,
+        // let it finish before the caller removes that state.
+        if matches!(
+            rx.recv_timeout(START_ABORT_GRACE),
+            Err(mpsc::RecvTimeoutError::Timeout)
+        ) {
+            log::warn!(
+                "start of container {} did not return after SIGKILL",
+                self.id
+            );
+        }
+        Err(ShimError::Engine(format!("container did not start within {:?}", timeout)).into())
+    }
+
+    /// Cleans up after a start that failed once the container was built. The
+    /// init process, if any, is killed and reaped, and its exit recorded so
+    /// that `wait` returns. The pidfile and the container's state are removed.
+    fn abort_start(&self, pid: Option<Pid>, pidfd: Option<RawFd>) {
+        let info = match pid {
+            Some(pid) => {
+                match nix::sys::signal::kill(pid, nix::sys::signal::SIGKILL) {
+                    Ok(()) | Err(Errno::ESRCH) => {}
+                    Err(err) => error!("failed to kill container that did not start: {}", err),
+                }
+                wait_exit(pid, pidfd)
+            }
+            None => ExitInfo::exited(UNKNOWN_EXIT_CODE),
+        };
+        record_exit(
+            &self.exit_code,
+            &self.exit_info,
+            info,
+            &self.exit_status_file(),
+        );
+        if pid.is_some() {
+            self.remove_pidfile(pid);
+        }
+        self.invalidate_container_cache();
+        match load_container(&self.rootdir, self.id.as_str()) {
+            Ok(mut container) => {
+                if let Err(err) = container.delete(true) {
+                    log::warn!("failed to delete container that did not start: {}", err);
+                }
+            }
+            Err(ShimError::NotFound(_)) => {}
+            Err(err) => log::warn!("could not load container that did not start: {}", err),
+        }
+        self.remove_state_dir();
+    }
+
+    /// Loads the container, reusing the last loaded state while the state
+    /// file is unchanged. libcontainer rewrites the file on every state
+    /// change it makes, so its mtime tells when to reload. The process exiting
+    /// doesn't touch the file, so the status is always refreshed from the pid.
+    fn cached_container(&self) -> Result<Container, ShimError> {
+        let state_file =
+            construct_container_root(&self.rootdir, self.id.as_str())?.join(STATE_FILE);
+        let mtime = fs::metadata(&state_file).and_then(|m| m.modified()).ok();
+        let mut cache = self.container_cache.lock().unwrap();
+        if let (Some(mtime), Some((cached_mtime, container))) = (mtime, cache.as_ref()) {
+            if mtime == *cached_mtime {
+                let mut container = container.clone();
+                container.refresh_status().map_err(|err| {
+                    ShimError::Engine(format!(
+                        "could not refresh status of container {}: {}",
+                        self.id, err
+                    ))
+                })?;
+                return Ok(container);
+            }
+        }
+        let container = load_container(&self.rootdir, self.id.as_str())?;
+        *cache = mtime.map(|mtime| (mtime, container.clone()));
+        Ok(container)
+    }
+
+    fn invalidate_container_cache(&self) {
+        *self.container_cache.lock().unwrap() = None;
+    }
+
+    /// Whether the container runs a wasm workload. A spec that can't be
+    /// loaded counts as not wasm, so signals are delivered as usual.
+    fn runs_wasm(&self) -> bool {
+        match load_spec(&self.bundle) {
+            Ok(spec) => is_wasm_workload(&spec),
+            Err(err) => {
+                log::warn!("could not load spec: {:#}", err);
+                false
+            }
+        }
+    }
+
+    /// Applies the requested scheduling priority and the spec's
+    /// `process.oomScoreAdj` to the init process before it is started.
+    /// Without them the process keeps what it inherited from the shim.
+    fn apply_priority(&self, pid: Pid) -> Result<()> {
+        let spec = load_spec(&self.bundle)?;
+        if let Some(nice) = requested_nice(&spec)? {
+            log::info!(">>> Setting nice value {} for container {}", nice, self.id);
+            let res =
+                unsafe { libc::setpriority(libc::PRIO_PROCESS, pid.as_raw() as libc::id_t, nice) };
+            Errno::result(res).context("could not set process priority")?;
+        }
+        if let Some(adj) = requested_oom_score_adj(&spec)? {
+            log::info!(
+                ">>> Setting oom_score_adj {} for container {}",
+                adj,
+                self.id
+            );
+            fs::write(format!("/proc/{}/oom_score_adj", pid), adj.to_string())
+                .context("could not set oom_score_adj")?;
+        }
+        Ok(())
+    }
+}
+
+/// How the host mounts its cgroups.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum CgroupMode {
+    /// cgroup v1 only.
+    Legacy,
+    /// cgroup v1 controllers with a v2 hierarchy mounted at `unified`.
+    Hybrid,
+    /// cgroup v2 only.
+    Unified,
+}
+
+/// Tells the cgroup layout from the files under the cgroup mount point
+/// `root`: only a v2 hierarchy has `cgroup.controllers`.
+fn detect_cgroup_mode(root: &Path) -> CgroupMode {
+    if root.join("cgroup.controllers").exists() {
+        CgroupMode::Unified
+    } else if root.join("unified/cgroup.controllers").exists() {
+        CgroupMode::Hybrid
+    } else {
+        CgroupMode::Legacy
+    }
+}
+
+/// Whether the container's cgroups are managed through systemd rather than
+/// the cgroupfs. The `systemd_cgroup` option wins. Otherwise systemd is used
+/// when the host runs systemd and the spec's `cgroupsPath` has systemd's
+/// `slice:prefix:name` form, which is what containerd generates with
+/// `SystemdCgroup = true`. libcgroups picks the v1 or v2 manager on its own,
+/// the detected mode is only logged.
+fn use_systemd_cgroup(options: &Options, spec: &Spec, cgroup_root: &Path) -> bool {
+    let mode = detect_cgroup_mode(cgroup_root);
+    let systemd = options.systemd_cgroup.unwrap_or_else(|| {
+        let systemd_path = spec
+            .linux()
+            .as_ref()
+            .and_then(|l| l.cgroups_path().as_ref())
+            .and_then(|p| p.to_str())
+            .is_some_and(|p| !p.starts_with('/') && p.split(':').count() == 3);
+        systemd_path && Path::new("/run/systemd/system").exists()
+    });
+    log::info!(
+        ">>> Host cgroup mode: {:?}, using {} cgroup driver",
+        mode,
+        if systemd { "systemd" } else { "cgroupfs" }
+    );
+    systemd
 }
 
-fn container_exists<P: AsRef<Path>>(root_path: P, container_id: &str) -> Result<bool> {
+/// Logs what a container is built from for `YOUKI_SHIM_DEBUG`.
+fn log_spec(spec: &Spec, executors: &[Box<dyn Executor>]) {
+    for line in describe_spec(spec, executors) {
+        log::debug!("{}", line);
+    }
+}
+
+/// The lines `log_spec` logs. Env values may hold secrets, so only their
+/// keys are included.
+fn describe_spec(spec: &Spec, executors: &[Box<dyn Executor>]) -> Vec<String> {
+    let process = spec.process().as_ref();
+    let args = process.and_then(|p| p.args().as_ref());
+    let env_keys: Vec<&str> = process
+        .and_then(|p| p.env().as_ref())
+        .into_iter()
+        .flatten()
+        .map(|e| e.split_once('=').map_or(e.as_str(), |(k, _)| k))
+        .collect();
+    let mounts: Vec<String> = spec
+        .mounts()
+        .iter()
+        .flatten()
+        .map(|m| {
+            let source = m.source().as_ref().map_or(Path::new(""), |s| s.as_path());
+            format!("{} -> {}", source.display(), m.destination().display())
+        })
+        .collect();
+    let claimed = executors
+        .iter()
+        .find(|e| e.can_handle(spec))
+        .map_or("none", |e| e.name());
+    vec![
+        format!("spec args: {:?}", args),
+        format!("spec env keys: {:?}", env_keys),
+        format!("spec mounts: {:?}", mounts),
+        format!("spec annotations: {:?}", spec.annotations()),
+        format!("executor claiming the workload: {}", claimed),
+    ]
+}
+
+/// libcontainer writes the spec's `linux.sysctl` inside the container. Only
+/// sysctls scoped to a namespace the container gets are safe to set, others
+/// would change the host or its namespaces.
+fn validate_sysctls(spec: &Spec) -> Result<()> {
+    let linux = match spec.linux() {
+        Some(linux) => linux,
+        None => return Ok(()),
+    };
+    let sysctls = match linux.sysctl() {
+        Some(sysctls) => sysctls,
+        None => return Ok(()),
+    };
+    let has_namespace = |typ: LinuxNamespaceType| {
+        linux
+            .namespaces()
+            .as_ref()
+            .is_some_and(|ns| ns.iter().any(|n| n.typ() == typ))
+    };
+    for key in sysctls.keys() {
+        let namespace = if key.starts_with("net.") {
+            LinuxNamespaceType::Network
+        } else if key.starts_with("fs.mqueue.")
+            || key.starts_with("kernel.msg")
+            || key.starts_with("kernel.shm")
+            || key == "kernel.sem"
+        {
+            LinuxNamespaceType::Ipc
+        } else if key == "kernel.hostname" || key == "kernel.domainname" {
+            LinuxNamespaceType::Uts
+        } else {
+            bail!("sysctl {:?} is not namespaced and can't be set", key);
+        };
+        if !has_namespace(namespace) {
+            bail!(
+                "sysctl {:?} requires a {:?} namespace, which the spec doesn't create",
+                key,
+                namespace
+            );
+        }
+    }
+    Ok(())
+}
+
+/// The cgroup v2 controllers the container's cgroup can get, and the cgroup
+/// they were read from. With the cgroupfs driver libcgroups enables the
+/// controllers all the way down from the root itself, so whatever the root
+/// of the hierarchy has is available. With systemd the container only gets
+/// what systemd delegates to its slice.
+fn available_controllers(
+    root: &Path,
+    spec: &Spec,
+    systemd: bool,
+) -> Result<(PathBuf, Vec<String>)> {
+    if !systemd {
+        let path = root.join("cgroup.controllers");
+        let controllers = fs::read_to_string(&path)
+            .with_context(|| format!("could not read {}", path.display()))?;
+        let controllers = controllers.split_whitespace().map(str::to_string).collect();
+        return Ok((root.to_path_buf(), controllers));
+    }
+    let parent = cgroup_parent(root, spec);
+    let controllers = delegated_controllers(&parent)?;
+    Ok((parent, controllers))
+}
+
+/// The systemd slice's cgroup the container's cgroup is created in, below the
+/// cgroup v2 mount `root`. A `cgroupsPath` in systemd's `slice:prefix:name`
+/// form names the slice, whose cgroup nests the slices it is named after,
+/// e.g. `kubepods-burstable.slice` lives in `kubepods.slice`. Without a
+/// `cgroupsPath` libcontainer puts the container in `system.slice`.
+fn cgroup_parent(root: &Path, spec: &Spec) -> PathBuf {
+    let slice = spec
+        .linux()
+        .as_ref()
+        .and_then(|l| l.cgroups_path().as_ref())
+        .and_then(|p| p.to_str())
+        .and_then(|p| p.split(':').next())
+        .filter(|s| !s.is_empty())
+        .unwrap_or("system.slice");
+    let mut dir = root.to_path_buf();
+    let name = slice.strip_suffix(".slice").unwrap_or(slice);
+    // "-.slice" is the root slice
+    if name != "-" {
+        let parts: Vec<&str> = name.split('-').collect();
+        for i in 1..=parts.len() {
+            dir.push(format!("{}.slice", parts[..i].join("-")));
+        }
+    }
+    dir
+}
+
+/// The controllers the cgroup `parent` delegates to cgroups created in it:
+/// the `cgroup.subtree_control` of the closest existing cgroup. Cgroups that
+/// don't exist yet can only get what that one delegates.
+fn delegated_controllers(parent: &Path) -> Result<Vec<String>> {
+    let existing = parent
+        .ancestors()
+        .find(|dir| dir.join("cgroup.subtree_control").exists())
+        .ok_or_else(|| anyhow!("no cgroup found at or above {}", parent.display()))?;
+    let path = existing.join("cgroup.subtree_control");
+    let controllers =
+        fs::read_to_string(&path).with_context(|| format!("could not read {}", path.display()))?;
+    Ok(controllers.split_whitespace().map(str::to_string).collect())
+}
+
+/// The cgroup v2 controllers the limits in `resources` are enforced by.
+fn required_controllers(resources: &LinuxResources) -> Vec<&'static str> {
+    let mut controllers = Vec::new();
+    if resources.memory().is_some() {
+        controllers.push("memory");
+    }
+    if resources.pids().is_some() {
+        controllers.push("pids");
+    }
+    if let Some(cpu) = resources.cpu() {
+        if cpu.shares().is_some() || cpu.quota().is_some() || cpu.period().is_some() {
+            controllers.push("cpu");
+        }
+        if cpu.cpus().is_some() || cpu.mems().is_some() {
+            controllers.push("cpuset");
+        }
+    }
+    if resources.block_io().is_some() {
+        controllers.push("io");
+    }
+    if resources.hugepage_limits().is_some() {
+        controllers.push("hugetlb");
+    }
+    controllers
+}
+
+/// Removes the limits enforced by `controller` from `resources`.
+fn drop_controller_limits(resources: &mut LinuxResources, controller: &str) {
+    match controller {
+        "memory" => {
+            resources.set_memory(None);
+        }
+        "pids" => {
+            resources.set_pids(None);
+        }
+        "cpu" | "cpuset" => {
+            // cpu and cpuset settings share one struct, keep the other half
+            if let Some(mut cpu) = resources.cpu().clone() {
+                if controller == "cpu" {
+                    cpu.set_shares(None).set_quota(None).set_period(None);
+                } else {
+                    cpu.set_cpus(None).set_mems(None);
+                }
+                resources.set_cpu(Some(cpu));
+            }
+        }
+        "io" => {
+            resources.set_block_io(None);
+        }
+        "hugetlb" => {
+            resources.set_hugepage_limits(None);
+        }
+        _ => {}
+    }
+}
+
+impl MyContainer {
+    /// Waits up to `timeout` for the container to exit. Returns `None` if it
+    /// is still running by then. Unlike `wait`, never blocks indefinitely.
+    pub fn wait_timeout(&self, timeout: Duration) -> Result<Option<ExitInfo>, Error> {
+        let (lock, cvar) = &*self.exit_code;
+        let ec = lock.lock().map_err(|err| Error::Others(err.to_string()))?;
+        let (ec, _) = cvar
+            .wait_timeout_while(ec, timeout, |ec| ec.is_none())
+            .map_err(|err| Error::Others(err.to_string()))?;
+        if ec.is_none() {
+            return Ok(None);
+        }
+        drop(ec);
+        Ok(self.exit_info())
+    }
+
+    /// How the container exited, once it has.
+    pub fn exit_info(&self) -> Option<ExitInfo> {
+        *self.exit_info.lock().unwrap()
+    }
+
+    /// Stops the container gracefully: sends `signal`, gives the process the
+    /// configured stop timeout to exit and escalates to SIGKILL otherwise.
+    pub fn stop(&self, signal: u32) -> Result<(), Error> {
+        let timeout = Duration::from_secs(
+            self.options
+                .stop_timeout_secs
+                .unwrap_or(DEFAULT_STOP_TIMEOUT_SECS),
+        );
+        if !timeout.is_zero() {
+            self.kill(signal)?;
+            if self.wait_timeout(timeout)?.is_some() {
+                return Ok(());
+            }
+            log::info!(
+                ">>> Container {} did not exit within {:?}, sending SIGKILL",
+                self.id,
+                timeout
+            );
+        }
+        self.kill(SIGKILL as u32)
+    }
+}
+
+fn load_spec<P: AsRef<Path>>(bundle: P) -> Result<Spec> {
+    let path = bundle.as_ref().join("config.json");
+    Spec::load(&path).with_context(|| format!("could not load spec from {}", path.display()))
+}
+
+/// Reads the nice value requested through the `youki.wasm.nice` annotation,
+/// clamped to the range accepted by the kernel.
+fn requested_nice(spec: &Spec) -> Result<Option<i32>> {
+    let nice = match WasmAnnotations::new(spec).nice()? {
+        Some(nice) => nice,
+        None => return Ok(None),
+    };
+    let clamped = nice.clamp(-20, 19);
+    if clamped != nice {
+        log::warn!("nice value {} out of range, clamping to {}", nice, clamped);
+    }
+    Ok(Some(clamped))
+}
+
+/// The spec's `process.oomScoreAdj`, which has to be in the kernel's range.
+fn requested_oom_score_adj(spec: &Spec) -> Result<Option<i32>> {
+    let adj = match spec.process().as_ref().and_then(|p| p.oom_score_adj()) {
+        Some(adj) => adj,
+        None => return Ok(None),
+    };
+    if !(-1000..=1000).contains(&adj) {
+        bail!(
+            "invalid oomScoreAdj {}: must be between -1000 and 1000",
+            adj
+        );
+    }
+    Ok(Some(adj))
+}
+
+/// Every value the `youki.wasm.handler` annotation may name, whether or not
+/// the executor is compiled into this build.
+const SUPPORTED_HANDLERS: &[&str] = &["wasmtime", "wasmedge", "default"];
+
+/// The executors tried in order for a workload. The wasm executors only claim
+/// workloads meant for them, anything else runs with youki's default executor.
+fn executors(options: &Options) -> Vec<Box<dyn Executor>> {
+    let default_env: Vec<(String, String)> = options
+        .default_env
+        .iter()
+        .flatten()
+        .map(|(k, v)| (k.clone(), v.clone()))
+        .collect();
+    // validated when the instance was created
+    let opt_level = default_opt_level(options).unwrap_or_default();
+    let wasmtime = WasmtimeExecutor::new(
+        options.engine_features.clone().unwrap_or_default(),
+        default_env.clone(),
+        opt_level,
+    );
+    let mut executors: Vec<Box<dyn Executor>> = vec![Box::new(wasmtime)];
+    #[cfg(feature = "wasmedge")]
+    executors.push(Box::new(WasmEdgeExecutor::new(default_env, opt_level)));
+    executors.push(Box::<DefaultExecutor>::default());
+    executors
+}
+
+/// Checks that the `youki.wasm.handler` annotation, if any, names one of
+/// `executors`. Without a matching executor the workload would silently fall
+/// through to the default executor and fail in confusing ways.
+fn check_handler(spec: &Spec, executors: &[Box<dyn Executor>]) -> Result<()> {
+    let handler = match WasmAnnotations::new(spec).handler() {
+        Some(handler) => handler,
+        None => return Ok(()),
+    };
+    if !SUPPORTED_HANDLERS
+        .iter()
+        .any(|h| h.eq_ignore_ascii_case(handler))
+    {
+        bail!(
+            "unknown handler '{}', expected one of {}",
+            handler,
+            SUPPORTED_HANDLERS.join(", ")
+        );
+    }
+    if !executors
+        .iter()
+        .any(|e| e.name().eq_ignore_ascii_case(handler))
+    {
+        bail!("no executor compiled for handler '{}'", handler);
+    }
+    Ok(())
+}
+
+/// Whether one of the wasm executors claims the workload.
+fn is_wasm_workload(spec: &Spec) -> bool {
+    let wasm = WasmtimeExecutor::default().can_handle(spec);
+    #[cfg(feature = "wasmedge")]
+    let wasm = wasm || WasmEdgeExecutor::default().can_handle(spec);
+    wasm
+}
+
+fn container_exists<P: AsRef<Path>>(root_path: P, container_id: &str) -> Result<bool, ShimError> {
     let container_root = construct_container_root(root_path, container_id)?;
     Ok(container_root.exists())
 }
 
-fn construct_container_root<P: AsRef<Path>>(root_path: P, container_id: &str) -> Result<PathBuf> {
+fn construct_container_root<P: AsRef<Path>>(
+    root_path: P,
+    container_id: &str,
+) -> Result<PathBuf, ShimError> {
     let root_path = fs::canonicalize(&root_path)?;
     Ok(root_path.join(container_id))
 }
 
-fn load_container<P: AsRef<Path>>(root_path: P, container_id: &str) -> Result<Container> {
+fn load_container<P: AsRef<Path>>(
+    root_path: P,
+    container_id: &str,
+) -> Result<Container, ShimError> {
     let container_root = construct_container_root(root_path, container_id)?;
     if !container_root.exists() {
-        bail!("container {} does not exist.", container_id)
+        return Err(ShimError::NotFound(format!(
+            "container {} does not exist",
+            container_id
+        )));
     }
 
-    Container::load(container_root)
-        .with_context(|| format!("could not load state for container {container_id}"))
+    Container::load(container_root).map_err(|err| {
+        ShimError::Engine(format!(
+            "could not load state for container {container_id}: {err}"
+        ))
+    })
 }
 
 impl EngineGetter for MyContainer {
     type E = ();
+    /// Called once by `ShimCli::new`, after containerd-shim set up its
+    /// logger, which makes it the place to raise the log level.
     fn new_engine() -> Result<Self::E, Error> {
+        logging::apply_debug_level();
         Ok(())
     }
 }
 
+/// The runtime name to register under. containerd runs
+/// `io.containerd.youki.v2` as `containerd-shim-youki-v2`, so a binary
+/// installed under that name serves v2 and anything else v1, unless
+/// `YOUKI_SHIM_NAME` says otherwise.
+fn shim_name() -> String {
+    resolve_shim_name(
+        std::env::var(SHIM_NAME_ENV).ok(),
+        std::env::args_os().next().map(PathBuf::from),
+    )
+}
+
+fn resolve_shim_name(env: Option<String>, program: Option<PathBuf>) -> String {
+    if let Some(name) = env.filter(|name| !name.is_empty()) {
+        return name;
+    }
+    let v2 = program
+        .and_then(|p| p.file_name().map(|n| n.to_owned()))
+        .is_some_and(|n| n == "containerd-shim-youki-v2");
+    if v2 {
+        SHIM_NAME_V2.to_string()
+    } else {
+        SHIM_NAME_V1.to_string()
+    }
+}
+
+/// The value of containerd-shim's `-<name>` flag, given as `-name value` or
+/// `-name=value` with one or two dashes.
+fn shim_flag(name: &str) -> Option<String> {
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let flag = match arg.strip_prefix("--").or_else(|| arg.strip_prefix('-')) {
+            Some(flag) => flag,
+            None => continue,
+        };
+        if flag == name {
+            return args.next();
+        }
+        if let Some(value) = flag.strip_prefix(name).and_then(|v| v.strip_prefix('=')) {
+            return Some(value.to_string());
+        }
+    }
+    None
+}
+
+/// Whether this is the long-running shim serving containerd's task API
+/// rather than a `start` or `delete` invocation, which containerd passes as
+/// the last argument.
+fn is_serving() -> bool {
+    !matches!(
+        std::env::args().skip(1).last().as_deref(),
+        Some("start" | "delete")
+    )
+}
+
 fn main() {
-    shim::run::<ShimCli<MyContainer, _>>("io.containerd.youki.v1", None);
+    if std::env::args_os().nth(1).as_deref() == Some(OsStr::new("validate")) {
+        // skip the binary name so that "validate" is the program name
+        let args = validate::ValidateArgs::parse_from(std::env::args_os().skip(1));
+        if let Err(err) = validate::run(&args) {
+            eprintln!("invalid bundle: {:#}", err);
+            std::process::exit(1);
+        }
+        println!("bundle is valid");
+        return;
+    }
+
+    if matches!(
+        std::env::args_os()
+            .nth(1)
+            .as_deref()
+            .and_then(OsStr::to_str),
+        Some("version" | "--version")
+    ) {
+        let args = version::VersionArgs::parse_from(std::env::args_os().skip(1));
+        if let Err(err) = version::run(&args) {
+            eprintln!("failed to print versions: {}", err);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    let config = logging::init(is_serving()).then(|| shim::Config {
+        no_setup_logger: true,
+        ..Default::default()
+    });
+    idle::spawn_watchdog();
+    if is_serving() {
+        metrics::spawn_listener(
+            &shim_flag("namespace").unwrap_or_default(),
+            &shim_flag("id").unwrap_or_default(),
+        );
+    }
+    // containerd-shim re-spawns the binary to serve the task, and the child
+    // has to register under the same name even if argv[0] differs
+    let name = shim_name();
+    std::env::set_var(SHIM_NAME_ENV, &name);
+    shim::run::<ShimCli<MyContainer, _>>(&name, config);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use oci_spec::runtime::{
+        LinuxCpuBuilder, LinuxMemoryBuilder, LinuxNamespaceBuilder, LinuxPidsBuilder,
+        LinuxResourcesBuilder, RootBuilder,
+    };
+    use tempfile::TempDir;
+    use youki_wasm_common::{HANDLER_ANNOTATION, NICE_ANNOTATION};
+
+    const TEST_ID: &str = "test-container";
+    /// A WASI module printing `HELLO_OUTPUT`, built from hello.wat.
+    const HELLO_WASM: &[u8] = include_bytes!("../test/fixtures/hello.wasm");
+    const HELLO_OUTPUT: &str = "hello from wasm\n";
+
+    /// A bundle keeping its state under its own root, so that the tests
+    /// don't touch the host's containers.
+    struct TestBundle {
+        bundle: TempDir,
+        _root: TempDir,
+    }
+
+    impl TestBundle {
+        fn new() -> Self {
+            let bundle = tempfile::tempdir().unwrap();
+            let root = tempfile::tempdir().unwrap();
+            let options = serde_json::json!({ "root": root.path() });
+            fs::write(bundle.path().join("options.json"), options.to_string()).unwrap();
+            TestBundle {
+                bundle,
+                _root: root,
+            }
+        }
+
+        /// Adds a rootfs holding `module` and a spec running it, otherwise
+        /// the defaults of oci-spec.
+        fn with_module(self, module: &[u8]) -> Self {
+            let rootfs = self.bundle.path().join("rootfs");
+            fs::create_dir(&rootfs).unwrap();
+            fs::write(rootfs.join("module.wasm"), module).unwrap();
+
+            let mut spec = Spec::default();
+            let mut process = spec.process().clone().unwrap_or_default();
+            process
+                .set_args(Some(vec!["/module.wasm".to_string()]))
+                .set_terminal(Some(false));
+            spec.set_process(Some(process));
+            let root = RootBuilder::default()
+                .path("rootfs")
+                .readonly(false)
+                .build()
+                .unwrap();
+            spec.set_root(Some(root));
+            spec.save(self.bundle.path().join("config.json")).unwrap();
+            self
+        }
+
+        fn config(&self) -> InstanceConfig<()> {
+            let mut cfg = InstanceConfig::new((), DEFAULT_NAMESPACE.to_string());
+            cfg.set_bundle(self.bundle.path().to_string_lossy().into_owned());
+            cfg
+        }
+
+        fn instance(&self) -> MyContainer {
+            MyContainer::new(TEST_ID.to_string(), Some(&self.config()))
+        }
+    }
+
+    #[test]
+    #[ignore = "needs root to create namespaces and cgroups"]
+    fn runs_wasm_lifecycle() {
+        let bundle = TestBundle::new().with_module(HELLO_WASM);
+        let stdout = bundle.bundle.path().join("stdout");
+        File::create(&stdout).unwrap();
+        let mut cfg = bundle.config();
+        cfg.set_stdout(stdout.to_string_lossy().into_owned());
+        // unique, since the cgroup is shared with the host
+        let id = format!("lifecycle-{}", std::process::id());
+        let container = MyContainer::new(id, Some(&cfg));
+
+        container.start().unwrap();
+        let (tx, rx) = mpsc::channel();
+        container.wait(&Wait::new(tx)).unwrap();
+        let exit = rx.recv_timeout(Duration::from_secs(60));
+        container.delete().unwrap();
+
+        let (code, _) = exit.unwrap();
+        assert_eq!(code, 0);
+        assert_eq!(fs::read_to_string(&stdout).unwrap(), HELLO_OUTPUT);
+    }
+
+    #[test]
+    fn resolves_namespace_overrides() {
+        let mut options = Options::default();
+        assert_eq!(
+            resolve_namespace(&options, "k8s.io".into()).unwrap(),
+            "k8s.io"
+        );
+        assert_eq!(
+            resolve_namespace(&options, String::new()).unwrap(),
+            DEFAULT_NAMESPACE
+        );
+        options.namespace = Some("custom".into());
+        assert_eq!(
+            resolve_namespace(&options, "k8s.io".into()).unwrap(),
+            "custom"
+        );
+        for namespace in ["..", "a/b", "a\0b"] {
+            options.namespace = Some(namespace.into());
+            let res = resolve_namespace(&options, "k8s.io".into());
+            assert!(
+                matches!(res, Err(Error::InvalidArgument(_))),
+                "{:?}",
+                namespace
+            );
+        }
+    }
+
+    fn annotated(key: &str, value: &str) -> Spec {
+        let mut spec = Spec::default();
+        spec.set_annotations(Some(HashMap::from([(key.to_string(), value.to_string())])));
+        spec
+    }
+
+    #[test]
+    fn clamps_requested_nice() {
+        let nice = |value: &str| requested_nice(&annotated(NICE_ANNOTATION, value));
+        assert_eq!(nice("-30").unwrap(), Some(-20));
+        assert_eq!(nice("25").unwrap(), Some(19));
+        assert_eq!(nice(" 5 ").unwrap(), Some(5));
+        assert!(nice("high").is_err());
+        assert_eq!(requested_nice(&Spec::default()).unwrap(), None);
+    }
+
+    #[test]
+    fn validates_default_env() {
+        let mut options = Options::default();
+        assert!(validate_default_env(&options).is_ok());
+        options.default_env = Some(HashMap::from([("REGION".into(), "eu=1".into())]));
+        assert!(validate_default_env(&options).is_ok());
+        for (key, value) in [("", "x"), ("A=B", "x"), ("A\0", "x"), ("A", "x\0")] {
+            options.default_env = Some(HashMap::from([(key.into(), value.into())]));
+            let res = validate_default_env(&options);
+            assert!(matches!(res, Err(Error::InvalidArgument(_))), "{:?}", key);
+        }
+    }
+
+    /// Saves libcontainer state for `container` as if it had been built in
+    /// `status`, with the test process standing in for its init process.
+    fn save_state(container: &MyContainer, status: ContainerStatus) {
+        let root = container.rootdir.join(&container.id);
+        fs::create_dir_all(&root).unwrap();
+        let pid = Some(std::process::id() as i32);
+        Container::new(
+            &container.id,
+            status,
+            pid,
+            Path::new(&container.bundle),
+            &root,
+        )
+        .unwrap()
+        .save()
+        .unwrap();
+    }
+
+    #[test]
+    fn delete_refuses_running_container_without_force() {
+        let bundle = TestBundle::new();
+        let container = bundle.instance();
+        save_state(&container, ContainerStatus::Running);
+        let res = container.delete();
+        assert!(matches!(res, Err(Error::FailedPrecondition(_))));
+        assert!(container_exists(&container.rootdir, TEST_ID).unwrap());
+    }
+
+    #[test]
+    fn cached_container_reads_state_once() {
+        let bundle = TestBundle::new();
+        let container = bundle.instance();
+        save_state(&container, ContainerStatus::Running);
+        let state_file = container.rootdir.join(TEST_ID).join(STATE_FILE);
+        let mtime = fs::metadata(&state_file).unwrap().modified().unwrap();
+        let set_mtime = |mtime| {
+            let file = File::options().write(true).open(&state_file).unwrap();
+            file.set_modified(mtime).unwrap();
+        };
+        for _ in 0..3 {
+            let cached = container.cached_container().unwrap();
+            assert_eq!(cached.status(), ContainerStatus::Running);
+            // only a state file that changed since would be read again
+            fs::write(&state_file, "not json").unwrap();
+            set_mtime(mtime);
+        }
+        set_mtime(mtime + Duration::from_secs(1));
+        assert!(container.cached_container().is_err());
+    }
+
+    #[test]
+    fn validate_root_rejects_escapes() {
+        for root in ["run/youki", "/run/../etc", "/run/youki/..", "../youki"] {
+            let res = validate_root(Path::new(root));
+            assert!(matches!(res, Err(Error::InvalidArgument(_))), "{:?}", root);
+        }
+        let missing = Path::new("/nonexistent/youki");
+        assert_eq!(validate_root(missing).unwrap(), missing);
+    }
+
+    #[test]
+    fn validate_root_resolves_symlinks() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("state");
+        fs::create_dir(&target).unwrap();
+        let link = dir.path().join("link");
+        std::os::unix::fs::symlink(&target, &link).unwrap();
+        assert_eq!(
+            validate_root(&link).unwrap(),
+            fs::canonicalize(&target).unwrap()
+        );
+    }
+
+    #[test]
+    fn root_option_wins_over_root_env() {
+        let env_root = tempfile::tempdir().unwrap();
+        let option_root = tempfile::tempdir().unwrap();
+        let ns = || "k8s.io".to_string();
+        // the only test touching ROOT_ENV, the others configure their root
+        // through options.json
+        std::env::set_var(ROOT_ENV, env_root.path());
+        let mut options = Options::default();
+        let from_env = determine_rootdir(&options, ns(), None);
+        options.root = Some(option_root.path().to_path_buf());
+        let from_option = determine_rootdir(&options, ns(), None);
+        std::env::set_var(ROOT_ENV, "");
+        options.root = None;
+        let empty_env = determine_rootdir(&options, ns(), None);
+        std::env::remove_var(ROOT_ENV);
+
+        let canonical = |dir: &TempDir| fs::canonicalize(dir.path()).unwrap().join(ns());
+        assert_eq!(from_env.unwrap(), canonical(&env_root));
+        assert_eq!(from_option.unwrap(), canonical(&option_root));
+        assert_eq!(
+            empty_env.unwrap(),
+            Path::new(DEFAULT_CONTAINER_ROOT_DIR).join(ns())
+        );
+    }
+
+    fn with_sysctls(sysctls: &[&str], namespaces: &[LinuxNamespaceType]) -> Spec {
+        let mut spec = Spec::default();
+        let mut linux = spec.linux().clone().unwrap_or_default();
+        let namespaces = namespaces
+            .iter()
+            .map(|typ| LinuxNamespaceBuilder::default().typ(*typ).build().unwrap())
+            .collect();
+        linux.set_namespaces(Some(namespaces)).set_sysctl(Some(
+            sysctls
+                .iter()
+                .map(|key| (key.to_string(), "1".to_string()))
+                .collect(),
+        ));
+        spec.set_linux(Some(linux));
+        spec
+    }
+
+    #[test]
+    fn allows_sysctls_of_created_namespaces() {
+        use LinuxNamespaceType::{Ipc, Network, Uts};
+        let sysctls = ["net.ipv4.ip_forward", "kernel.shmmax", "kernel.hostname"];
+        assert!(validate_sysctls(&with_sysctls(&sysctls, &[Network, Ipc, Uts])).is_ok());
+        assert!(validate_sysctls(&with_sysctls(&[], &[])).is_ok());
+    }
+
+    #[test]
+    fn rejects_host_sysctls() {
+        use LinuxNamespaceType::{Ipc, Network, Uts};
+        let all = [Network, Ipc, Uts];
+        assert!(validate_sysctls(&with_sysctls(&["vm.swappiness"], &all)).is_err());
+        assert!(validate_sysctls(&with_sysctls(&["kernel.panic"], &all)).is_err());
+        // namespaced, but shared with the host without the namespace
+        assert!(validate_sysctls(&with_sysctls(&["net.ipv4.ip_forward"], &[Ipc])).is_err());
+        assert!(validate_sysctls(&with_sysctls(&["kernel.sem"], &[Network])).is_err());
+    }
+
+    #[test]
+    fn load_options_accepts_missing_and_empty_files() {
+        let bundle = tempfile::tempdir().unwrap();
+        assert!(load_options(bundle.path()).unwrap().root.is_none());
+        fs::write(bundle.path().join("options.json"), " \n").unwrap();
+        assert!(load_options(bundle.path()).unwrap().root.is_none());
+        fs::write(bundle.path().join("options.json"), r#"{"root": "/x"}"#).unwrap();
+        assert_eq!(
+            load_options(bundle.path()).unwrap().root,
+            Some(PathBuf::from("/x"))
+        );
+    }
+
+    #[test]
+    fn load_options_rejects_truncated_files() {
+        let bundle = tempfile::tempdir().unwrap();
+        fs::write(bundle.path().join("options.json"), r#"{"root": "/x""#).unwrap();
+        assert!(load_options(bundle.path()).is_err());
+    }
+
+    #[test]
+    fn detects_cgroup_mode_from_layout() {
+        let root = tempfile::tempdir().unwrap();
+        assert_eq!(detect_cgroup_mode(root.path()), CgroupMode::Legacy);
+        fs::create_dir(root.path().join("unified")).unwrap();
+        fs::write(root.path().join("unified/cgroup.controllers"), "").unwrap();
+        assert_eq!(detect_cgroup_mode(root.path()), CgroupMode::Hybrid);
+        fs::write(root.path().join("cgroup.controllers"), "cpu memory").unwrap();
+        assert_eq!(detect_cgroup_mode(root.path()), CgroupMode::Unified);
+    }
+
+    fn exit(container: &MyContainer, code: i32) {
+        record_exit(
+            &container.exit_code,
+            &container.exit_info,
+            ExitInfo::exited(code),
+            &container.exit_status_file(),
+        );
+    }
+
+    #[test]
+    fn wait_timeout_returns_exit() {
+        let bundle = TestBundle::new();
+        let container = bundle.instance();
+        let waited = container.wait_timeout(Duration::from_millis(10)).unwrap();
+        assert!(waited.is_none());
+        exit(&container, 3);
+        let info = container.wait_timeout(Duration::from_millis(10)).unwrap();
+        assert_eq!(info.map(|i| i.code), Some(3));
+        assert_eq!(container.exit_info().map(|i| i.code), Some(3));
+    }
+
+    #[test]
+    fn resolves_shim_name() {
+        let v2 = Some(PathBuf::from("/usr/bin/containerd-shim-youki-v2"));
+        let v1 = Some(PathBuf::from("/usr/bin/containerd-shim-youki-v1"));
+        assert_eq!(resolve_shim_name(None, v2.clone()), SHIM_NAME_V2);
+        assert_eq!(resolve_shim_name(None, v1.clone()), SHIM_NAME_V1);
+        assert_eq!(resolve_shim_name(None, None), SHIM_NAME_V1);
+        assert_eq!(resolve_shim_name(Some(String::new()), v2), SHIM_NAME_V2);
+        assert_eq!(
+            resolve_shim_name(Some("io.containerd.custom.v2".to_string()), v1),
+            "io.containerd.custom.v2"
+        );
+    }
+
+    fn stdin_fifo(dir: &TempDir) -> String {
+        let path = dir.path().join("stdin");
+        nix::unistd::mkfifo(&path, nix::sys::stat::Mode::S_IRWXU).unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn stdin_fifo_waits_for_writer() {
+        let dir = TempDir::new().unwrap();
+        let path = stdin_fifo(&dir);
+        let writer_path = path.clone();
+        let writer = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(200));
+            let mut f = OpenOptions::new().write(true).open(writer_path).unwrap();
+            f.write_all(b"input").unwrap();
+        });
+        let fd = maybe_open_stdio(&path, true, false).unwrap().unwrap();
+        writer.join().unwrap();
+        let mut input = String::new();
+        std::io::Read::read_to_string(&mut File::from(fd), &mut input).unwrap();
+        assert_eq!(input, "input");
+    }
+
+    #[test]
+    fn stdin_fifo_without_writer_reads_eof() {
+        let dir = TempDir::new().unwrap();
+        let path = stdin_fifo(&dir);
+        let mut f = open_fifo_reader(&path, Duration::from_millis(100)).unwrap();
+        let mut input = String::new();
+        std::io::Read::read_to_string(&mut f, &mut input).unwrap();
+        assert!(input.is_empty());
+    }
+
+    #[test]
+    fn detects_transient_errors() {
+        for errno in [Errno::EBUSY, Errno::EAGAIN] {
+            assert!(is_transient(&anyhow::Error::from(errno)), "{}", errno);
+            let io = std::io::Error::from_raw_os_error(errno as i32);
+            assert!(is_transient(&anyhow::Error::from(io)), "{}", errno);
+            let deep = Err::<(), _>(errno)
+                .context("create cgroup")
+                .context("build container")
+                .unwrap_err();
+            assert!(is_transient(&deep), "{}", errno);
+        }
+        assert!(!is_transient(&anyhow::Error::from(Errno::ENOENT)));
+        let io = std::io::Error::from_raw_os_error(libc::EPERM);
+        assert!(!is_transient(&anyhow::Error::from(io).context("build")));
+        assert!(!is_transient(&anyhow!("engine failed")));
+    }
+
+    #[test]
+    fn validates_pidfile_path() {
+        assert!(validate_pidfile(Path::new("/run/app/init.pid")).is_ok());
+        for path in ["init.pid", "/run/../etc/init.pid", "/"] {
+            assert!(
+                matches!(
+                    validate_pidfile(Path::new(path)),
+                    Err(Error::InvalidArgument(_))
+                ),
+                "{}",
+                path
+            );
+        }
+    }
+
+    #[test]
+    fn writes_pidfile() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("init.pid");
+        write_pidfile(&path, Pid::from_raw(42)).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "42");
+        assert!(is_pidfile(&path, Some(Pid::from_raw(42))));
+        // a pidfile left by a previous start is replaced
+        write_pidfile(&path, Pid::from_raw(43)).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "43");
+        // no temporary file is left behind
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn write_pidfile_refuses_other_files() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("config");
+        fs::write(&path, "not a pid").unwrap();
+        assert!(write_pidfile(&path, Pid::from_raw(42)).is_err());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "not a pid");
+
+        let missing = dir.path().join("missing").join("init.pid");
+        assert!(write_pidfile(&missing, Pid::from_raw(42)).is_err());
+        assert!(!missing.exists());
+    }
+
+    #[test]
+    fn checks_handler_annotation() {
+        let executors = executors(&Options::default());
+        assert!(check_handler(&Spec::default(), &executors).is_ok());
+        for handler in ["wasmtime", "Default"] {
+            let spec = annotated(HANDLER_ANNOTATION, handler);
+            assert!(check_handler(&spec, &executors).is_ok(), "{}", handler);
+        }
+
+        // a supported handler whose executor isn't in this build
+        let default_only: Vec<Box<dyn Executor>> = vec![Box::<DefaultExecutor>::default()];
+        let spec = annotated(HANDLER_ANNOTATION, "wasmtime");
+        let err = check_handler(&spec, &default_only).unwrap_err();
+        assert!(err.to_string().contains("no executor compiled"), "{}", err);
+    }
+
+    #[test]
+    fn rejects_unknown_handler() {
+        let spec = annotated(HANDLER_ANNOTATION, "bogus");
+        let err = check_handler(&spec, &executors(&Options::default())).unwrap_err();
+        assert!(
+            err.to_string().contains("unknown handler 'bogus'"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn state_subdir_adds_a_level_below_the_namespace() {
+        let root = tempfile::tempdir().unwrap();
+        let options = Options {
+            root: Some(root.path().to_path_buf()),
+            ..Default::default()
+        };
+        let rootdir = |spec: &Spec| determine_rootdir(&options, "k8s.io".to_string(), Some(spec));
+        let namespace_dir = fs::canonicalize(root.path()).unwrap().join("k8s.io");
+
+        assert_eq!(rootdir(&Spec::default()).unwrap(), namespace_dir);
+        let spec = annotated(STATE_SUBDIR_ANNOTATION, "pod-a");
+        assert_eq!(rootdir(&spec).unwrap(), namespace_dir.join("pod-a"));
+
+        for subdir in ["..", ".", "", "a/b", "/abs"] {
+            let spec = annotated(STATE_SUBDIR_ANNOTATION, subdir);
+            assert!(
+                matches!(rootdir(&spec), Err(Error::InvalidArgument(_))),
+                "{:?}",
+                subdir
+            );
+        }
+    }
+
+    fn with_cgroups_path(path: &str) -> Spec {
+        let mut spec = Spec::default();
+        let mut linux = spec.linux().clone().unwrap_or_default();
+        linux.set_cgroups_path(Some(PathBuf::from(path)));
+        spec.set_linux(Some(linux));
+        spec
+    }
+
+    #[test]
+    fn cgroup_parent_nests_systemd_slices() {
+        let root = Path::new("/sys/fs/cgroup");
+        let spec = with_cgroups_path("kubepods-burstable.slice:cri-containerd:abc");
+        assert_eq!(
+            cgroup_parent(root, &spec),
+            root.join("kubepods.slice/kubepods-burstable.slice")
+        );
+        let spec = with_cgroups_path("-.slice:youki:abc");
+        assert_eq!(cgroup_parent(root, &spec), root);
+        assert_eq!(
+            cgroup_parent(root, &Spec::default()),
+            root.join("system.slice")
+        );
+    }
+
+    fn limited_resources() -> LinuxResources {
+        let memory = LinuxMemoryBuilder::default()
+            .limit(1 << 20)
+            .build()
+            .unwrap();
+        let pids = LinuxPidsBuilder::default().limit(10).build().unwrap();
+        let cpu = LinuxCpuBuilder::default()
+            .shares(512u64)
+            .cpus("0-1")
+            .build()
+            .unwrap();
+        LinuxResourcesBuilder::default()
+            .memory(memory)
+            .pids(pids)
+            .cpu(cpu)
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn required_controllers_follow_limits() {
+        assert_eq!(
+            required_controllers(&limited_resources()),
+            ["memory", "pids", "cpu", "cpuset"]
+        );
+        let none = LinuxResourcesBuilder::default().build().unwrap();
+        assert!(required_controllers(&none).is_empty());
+    }
+
+    #[test]
+    fn drops_only_the_controllers_limits() {
+        let mut resources = limited_resources();
+        drop_controller_limits(&mut resources, "cpu");
+        let cpu = resources.cpu().clone().unwrap();
+        assert_eq!(cpu.shares(), &None);
+        assert_eq!(cpu.cpus().as_deref(), Some("0-1"));
+        drop_controller_limits(&mut resources, "memory");
+        assert!(resources.memory().is_none());
+        assert!(resources.pids().is_some());
+        assert_eq!(required_controllers(&resources), ["pids", "cpuset"]);
+    }
+
+    /// A cgroup v2 mount whose root has `controllers`, with `system.slice`
+    /// delegating `delegated`.
+    fn fake_cgroupfs(controllers: &str, delegated: &str) -> TempDir {
+        let root = tempfile::tempdir().unwrap();
+        fs::write(root.path().join("cgroup.controllers"), controllers).unwrap();
+        fs::write(root.path().join("cgroup.subtree_control"), controllers).unwrap();
+        let slice = root.path().join("system.slice");
+        fs::create_dir(&slice).unwrap();
+        fs::write(slice.join("cgroup.subtree_control"), delegated).unwrap();
+        root
+    }
+
+    #[test]
+    fn checks_cgroup_controllers_against_the_driver() {
+        let bundle = TestBundle::new().with_module(HELLO_WASM);
+        let mut spec = load_spec(bundle.bundle.path()).unwrap();
+        let mut linux = spec.linux().clone().unwrap_or_default();
+        linux.set_resources(Some(limited_resources()));
+        spec.set_linux(Some(linux));
+        let cgroups = fake_cgroupfs("cpu cpuset memory pids", "cpu pids");
+        let mut container = bundle.instance();
+
+        // cgroupfs enables what the root has, systemd only what is delegated
+        let res = container.check_cgroup_controllers(&spec, false, cgroups.path());
+        assert_eq!(res.unwrap(), None);
+        let adjusted = container
+            .check_cgroup_controllers(&spec, true, cgroups.path())
+            .unwrap()
+            .unwrap();
+        let linux = load_spec(&adjusted).unwrap().linux().clone().unwrap();
+        let resources = linux.resources().clone().unwrap();
+        assert!(resources.memory().is_none());
+        assert_eq!(resources.cpu().clone().unwrap().cpus(), &None);
+        assert!(resources.pids().is_some());
+
+        container.options.strict_cgroups = Some(true);
+        let err = container
+            .check_cgroup_controllers(&spec, true, cgroups.path())
+            .unwrap_err();
+        assert!(err.to_string().contains("\"memory\""), "{}", err);
+    }
+
+    #[test]
+    fn validates_oom_score_adj() {
+        let with_adj = |adj: i32| {
+            let mut spec = Spec::default();
+            let mut process = spec.process().clone().unwrap_or_default();
+            process.set_oom_score_adj(Some(adj));
+            spec.set_process(Some(process));
+            spec
+        };
+        assert_eq!(requested_oom_score_adj(&Spec::default()).unwrap(), None);
+        assert_eq!(
+            requested_oom_score_adj(&with_adj(-1000)).unwrap(),
+            Some(-1000)
+        );
+        assert_eq!(requested_oom_score_adj(&with_adj(500)).unwrap(), Some(500));
+        assert!(requested_oom_score_adj(&with_adj(1001)).is_err());
+        assert!(requested_oom_score_adj(&with_adj(-1001)).is_err());
+    }
+
+    #[test]
+    fn describe_spec_leaves_out_env_values() {
+        let mut spec = Spec::default();
+        let mut process = spec.process().clone().unwrap_or_default();
+        process
+            .set_args(Some(vec!["/app.wasm".to_string()]))
+            .set_env(Some(vec![
+                "API_TOKEN=hunter2".to_string(),
+                "EMPTY=".to_string(),
+            ]));
+        spec.set_process(Some(process));
+        let lines = describe_spec(&spec, &executors(&Options::default())).join("\n");
+        assert!(lines.contains("\"API_TOKEN\""), "{}", lines);
+        assert!(lines.contains("\"EMPTY\""), "{}", lines);
+        assert!(!lines.contains("hunter2"), "{}", lines);
+        assert!(
+            lines.contains("executor claiming the workload: wasmtime"),
+            "{}",
+            lines
+        );
+    }
 }
@@ -0,0 +1,201 @@
+//! Optional shim metrics. With `YOUKI_SHIM_METRICS_SOCKET` set, each serving
+//! shim counts container lifecycle events and serves them in the Prometheus
+//! text format on that Unix socket. Every shim serves its own counters, so
+//! `{namespace}` and `{id}` in the path are replaced with the shim's, e.g.
+//! `/run/youki-shim/{namespace}-{id}.sock` for
+//! `curl --unix-socket /run/youki-shim/default-web.sock http://localhost/metrics`.
+
+use std::fmt::Write as _;
+use std::fs;
+use std::io::{ErrorKind, Read, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicI64, AtomicU64, Ordering};
+use std::thread;
+use std::time::Duration;
+
+const METRICS_SOCKET_ENV: &str = "YOUKI_SHIM_METRICS_SOCKET";
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static STARTED: AtomicU64 = AtomicU64::new(0);
+static FAILED_STARTS: AtomicU64 = AtomicU64::new(0);
+static RUNNING: AtomicI64 = AtomicI64::new(0);
+static START_DURATION_MICROS: AtomicU64 = AtomicU64::new(0);
+static KILLS: AtomicU64 = AtomicU64::new(0);
+static DELETES: AtomicU64 = AtomicU64::new(0);
+
+fn count(counter: &AtomicU64, n: u64) {
+    if ENABLED.load(Ordering::Relaxed) {
+        counter.fetch_add(n, Ordering::Relaxed);
+    }
+}
+
+/// A container started successfully after `took`.
+pub fn started(took: Duration) {
+    count(&STARTED, 1);
+    count(&START_DURATION_MICROS, took.as_micros() as u64);
+    if ENABLED.load(Ordering::Relaxed) {
+        RUNNING.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+pub fn start_failed() {
+    count(&FAILED_STARTS, 1);
+}
+
+/// A container counted by `started` exited.
+pub fn exited() {
+    if ENABLED.load(Ordering::Relaxed) {
+        RUNNING.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+pub fn killed() {
+    count(&KILLS, 1);
+}
+
+pub fn deleted() {
+    count(&DELETES, 1);
+}
+
+fn render() -> String {
+    let mut out = String::new();
+    let metrics = [
+        (
+            "youki_shim_containers_started_total",
+            "counter",
+            "Containers started.",
+            STARTED.load(Ordering::Relaxed).to_string(),
+        ),
+        (
+            "youki_shim_container_start_failures_total",
+            "counter",
+            "Container starts that failed.",
+            FAILED_STARTS.load(Ordering::Relaxed).to_string(),
+        ),
+        (
+            "youki_shim_containers_running",
+            "gauge",
+            "Containers currently running.",
+            RUNNING.load(Ordering::Relaxed).to_string(),
+        ),
+        (
+            "youki_shim_container_start_seconds_total",
+            "counter",
+            "Time spent starting containers, divide by the started total for the average.",
+            format!(
+                "{:.6}",
+                START_DURATION_MICROS.load(Ordering::Relaxed) as f64 / 1e6
+            ),
+        ),
+        (
+            "youki_shim_container_kills_total",
+            "counter",
+            "Signals sent to containers.",
+            KILLS.load(Ordering::Relaxed).to_string(),
+        ),
+        (
+            "youki_shim_container_deletes_total",
+            "counter",
+            "Containers deleted.",
+            DELETES.load(Ordering::Relaxed).to_string(),
+        ),
+    ];
+    for (name, kind, help, value) in metrics {
+        let _ = writeln!(out, "# HELP {name} {help}");
+        let _ = writeln!(out, "# TYPE {name} {kind}");
+        let _ = writeln!(out, "{name} {value}");
+    }
+    out
+}
+
+/// Answers any request on the connection with the current metrics.
+fn serve(mut stream: UnixStream) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(1)))?;
+    // The request itself doesn't matter, read what the client sent so far
+    // so that it isn't reset before reading the response.
+    let mut buf = [0u8; 1024];
+    match stream.read(&mut buf) {
+        Ok(_) => {}
+        Err(err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}
+        Err(err) => return Err(err),
+    }
+    let body = render();
+    write!(
+        stream,
+        "HTTP/1.0 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\n\r\n{}",
+        body.len(),
+        body
+    )
+}
+
+/// The metrics socket of the shim for `id` in `namespace`, `None` if either
+/// can't be part of a path.
+fn socket_path(template: &str, namespace: &str, id: &str) -> Option<PathBuf> {
+    if [namespace, id]
+        .iter()
+        .any(|part| part.is_empty() || part.contains(['/', '\0']))
+    {
+        return None;
+    }
+    let path = template
+        .replace("{namespace}", namespace)
+        .replace("{id}", id);
+    Some(PathBuf::from(path))
+}
+
+/// Starts serving metrics of the shim for `id` in `namespace` when a metrics
+/// socket is configured. Without it nothing is counted. Meant for the
+/// serving shim only, the short-lived `start` and `delete` invocations have
+/// nothing to report.
+pub fn spawn_listener(namespace: &str, id: &str) {
+    let template = match std::env::var(METRICS_SOCKET_ENV) {
+        Ok(template) if !template.is_empty() => template,
+        _ => return,
+    };
+    let Some(path) = socket_path(&template, namespace, id) else {
+        log::warn!("not serving metrics for {:?} in {:?}", id, namespace);
+        return;
+    };
+    // Only a socket left behind by a previous shim may be replaced, one that
+    // is still served belongs to a live shim.
+    if UnixStream::connect(&path).is_ok() {
+        log::warn!(
+            "metrics socket {} is served by another shim",
+            path.display()
+        );
+        return;
+    }
+    let _ = fs::remove_file(&path);
+    let listener = match UnixListener::bind(&path) {
+        Ok(listener) => listener,
+        Err(err) => {
+            log::warn!("could not listen for metrics on {:?}: {}", path, err);
+            return;
+        }
+    };
+    ENABLED.store(true, Ordering::Relaxed);
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let result = stream.and_then(serve);
+            if let Err(err) = result {
+                log::debug!("failed to serve metrics: {}", err);
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn socket_path_fills_in_namespace_and_id() {
+        let path = socket_path("/run/metrics/{namespace}-{id}.sock", "default", "web");
+        assert_eq!(path, Some(PathBuf::from("/run/metrics/default-web.sock")));
+        let path = socket_path("/run/metrics.sock", "default", "web");
+        assert_eq!(path, Some(PathBuf::from("/run/metrics.sock")));
+        assert_eq!(socket_path("/run/{id}.sock", "default", "../web"), None);
+        assert_eq!(socket_path("/run/{id}.sock", "", "web"), None);
+    }
+}
@@ -0,0 +1,74 @@
+//! `validate` subcommand: checks that a bundle would run, without creating a
+//! container. Handy in CI to tell whether an image is runnable.
+
+use anyhow::{Context, Result};
+use clap::Parser;
+use std::path::{Path, PathBuf};
+
+use crate::{
+    check_handler, default_opt_level, determine_rootdir, executors, load_options, load_spec,
+    requested_nice, requested_oom_score_adj, validate_default_env, validate_sysctls,
+    DEFAULT_NAMESPACE,
+};
+
+#[derive(Parser)]
+#[command(name = "validate", about = "Check that a bundle can be run")]
+pub struct ValidateArgs {
+    /// Path to the bundle directory
+    bundle: PathBuf,
+    /// Namespace used to resolve the container state directory
+    #[arg(long, default_value = DEFAULT_NAMESPACE)]
+    namespace: String,
+}
+
+pub fn run(args: &ValidateArgs) -> Result<()> {
+    let options = load_options(&args.bundle)?;
+    validate_default_env(&options)?;
+    let spec = load_spec(&args.bundle)?;
+    let rootdir = determine_rootdir(&options, args.namespace.clone(), Some(&spec))?;
+    println!("rootdir: {}", rootdir.display());
+
+    validate_sysctls(&spec)?;
+    requested_nice(&spec)?;
+    requested_oom_score_adj(&spec)?;
+    youki_wasm_common::opt_level(&spec, default_opt_level(&options)?)?;
+    let executors = executors(&options);
+    check_handler(&spec, &executors)?;
+
+    let rootfs = match spec.root() {
+        Some(root) => args.bundle.join(root.path()),
+        None => args.bundle.join("rootfs"),
+    };
+    // the default executor claims anything, it comes last
+    let executor = executors
+        .iter()
+        .find(|e| e.can_handle(&spec))
+        .map_or("default", |e| e.name());
+    println!("executor: {}", executor);
+    if youki_wasm_common::module_from_stdin(&spec) {
+        println!("module is read from stdin, skipping module validation");
+        return Ok(());
+    }
+    let module = match executor {
+        "wasmtime" => {
+            let features = options.engine_features.unwrap_or_default();
+            youki_wasmtime_executor::validate(&spec, &rootfs, &features)
+        }
+        #[cfg(feature = "wasmedge")]
+        "wasmedge" => youki_wasmedge_executor::validate(&spec, &rootfs),
+        _ => {
+            println!("not a wasm workload, skipping module validation");
+            return Ok(());
+        }
+    };
+    let module = module.context("module failed to validate")?;
+    println!("module: {}", display_in_rootfs(&module, &rootfs));
+    Ok(())
+}
+
+fn display_in_rootfs(path: &Path, rootfs: &Path) -> String {
+    match path.strip_prefix(rootfs) {
+        Ok(p) => format!("/{}", p.display()),
+        Err(_) => path.display().to_string(),
+    }
+}
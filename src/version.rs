@@ -0,0 +1,41 @@
+//! `version` subcommand: prints the shim version and the versions of the
+//! wasm engines compiled into it, e.g. for bug reports.
+
+use clap::Parser;
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+#[derive(Parser)]
+#[command(name = "version", about = "Print the shim and engine versions")]
+pub struct VersionArgs {
+    /// Print the versions as a JSON object
+    #[arg(long)]
+    json: bool,
+}
+
+#[derive(Serialize)]
+struct Versions {
+    shim: &'static str,
+    engines: BTreeMap<&'static str, &'static str>,
+}
+
+pub fn run(args: &VersionArgs) -> serde_json::Result<()> {
+    let mut engines = BTreeMap::new();
+    engines.insert("wasmtime", youki_wasmtime_executor::ENGINE_VERSION);
+    #[cfg(feature = "wasmedge")]
+    engines.insert("wasmedge", youki_wasmedge_executor::ENGINE_VERSION);
+    let versions = Versions {
+        shim: env!("CARGO_PKG_VERSION"),
+        engines,
+    };
+
+    if args.json {
+        println!("{}", serde_json::to_string(&versions)?);
+        return Ok(());
+    }
+    println!("{} {}", env!("CARGO_PKG_NAME"), versions.shim);
+    for (engine, version) in versions.engines {
+        println!("{} {}", engine, version);
+    }
+    Ok(())
+}
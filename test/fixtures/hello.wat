@@ -0,0 +1,13 @@
+;; Source of hello.wasm, the module the lifecycle test in src/main.rs runs.
+;; Writes "hello from wasm\n" to stdout and exits with 0.
+(module
+  (import "wasi_snapshot_preview1" "fd_write"
+    (func $fd_write (param i32 i32 i32 i32) (result i32)))
+  (memory (export "memory") 1)
+  (data (i32.const 8) "hello from wasm\n")
+  (func (export "_start")
+    ;; a single iovec at 0 pointing at the 16-byte message
+    (i32.store (i32.const 0) (i32.const 8))
+    (i32.store (i32.const 4) (i32.const 16))
+    (drop
+      (call $fd_write (i32.const 1) (i32.const 0) (i32.const 1) (i32.const 24)))))